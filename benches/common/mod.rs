@@ -0,0 +1,49 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Video tags are this big, one in `KEYFRAME_INTERVAL` is a keyframe.
+const VIDEO_TAG_DATA: usize = 4096;
+const KEYFRAME_INTERVAL: usize = 30;
+
+fn write_tag(out: &mut impl Write, tag_type: u8, timestamp: u32, data: &[u8]) -> io::Result<()> {
+    let size = data.len() as u32;
+    out.write_all(&[tag_type])?;
+    out.write_all(&size.to_be_bytes()[1..])?;
+    out.write_all(&timestamp.to_be_bytes()[1..])?;
+    out.write_all(&[(timestamp >> 24) as u8, 0, 0, 0])?;
+    out.write_all(data)?;
+    out.write_all(&(size + 11).to_be_bytes())
+}
+
+/// Writes a valid FLV of about `size` bytes: a header, an `onMetaData`
+/// script tag without keyframes and 30 fps H.264 video tags.
+pub fn write_flv(path: &Path, size: u64) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"FLV\x01\x01\x00\x00\x00\x09\x00\x00\x00\x00")?;
+
+    let frames = (size as usize / (VIDEO_TAG_DATA + 15)).max(1);
+    let mut metadata = vec![0x02, 0x00, 0x0a];
+    metadata.extend_from_slice(b"onMetaData");
+    metadata.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08]);
+    metadata.extend_from_slice(b"duration");
+    metadata.push(0x00);
+    metadata.extend_from_slice(&(frames as f64 / 30f64).to_be_bytes());
+    metadata.extend_from_slice(&[0x00, 0x00, 0x09]);
+    write_tag(&mut out, 18, 0, &metadata)?;
+
+    let mut video = vec![0u8; VIDEO_TAG_DATA];
+    for frame in 0..frames {
+        // frame type in the upper nibble, codec 7 is AVC, then a NALU packet
+        video[0] = if frame % KEYFRAME_INTERVAL == 0 {
+            0x17
+        } else {
+            0x27
+        };
+        video[1] = 0x01;
+        write_tag(&mut out, 9, (frame * 1000 / 30) as u32, &video)?;
+    }
+    out.flush()
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flv_keyframes::generate_patch;
+use std::env;
+use tokio::{fs::File, runtime::Runtime};
+
+/// Sizes in MB, e.g. `FLV_BENCH_MB=1,100,1024`.
+fn sizes() -> Vec<u64> {
+    env::var("FLV_BENCH_MB")
+        .unwrap_or_else(|_| "1,100".to_string())
+        .split(',')
+        .map(|mb| {
+            mb.trim()
+                .parse()
+                .expect("FLV_BENCH_MB is a list of numbers")
+        })
+        .collect()
+}
+
+fn bench_generate_patch(c: &mut Criterion) {
+    let mut runtime = Runtime::new().unwrap();
+    let dir = env::temp_dir();
+    let mut group = c.benchmark_group("generate_patch");
+    group.sample_size(10);
+    for mb in sizes() {
+        let path = dir.join(format!("flv-keyframes-bench-{}mb.flv", mb));
+        common::write_flv(&path, mb * 1024 * 1024).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        group.throughput(Throughput::Bytes(len));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}MB", mb)),
+            &path,
+            |b, path| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let file = File::open(path).await.unwrap();
+                        generate_patch(file).await.unwrap().patch.unwrap()
+                    })
+                })
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_generate_patch);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+mod common;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use flv_keyframes::{generate_patch, Patch};
+use std::{env, io::SeekFrom, path::Path};
+use tokio::{fs::File, prelude::*, runtime::Runtime};
+
+const READ_SIZE: usize = 4096;
+const RANDOM_READS: u64 = 1000;
+
+/// Reads `reader` to the end in `READ_SIZE` chunks.
+async fn read_sequential<R: AsyncRead + Unpin>(mut reader: R) -> u64 {
+    let mut buf = [0u8; READ_SIZE];
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buf).await.unwrap() {
+            0 => return total,
+            n => total += n as u64,
+        }
+    }
+}
+
+async fn open_patched(path: &Path, patch: &Patch) -> flv_keyframes::PatchedReader<File> {
+    patch
+        .patch_reader(File::open(path).await.unwrap())
+        .await
+        .unwrap()
+}
+
+fn bench_patch_reader(c: &mut Criterion) {
+    let mut runtime = Runtime::new().unwrap();
+    let mb: u64 = env::var("FLV_BENCH_READ_MB")
+        .map(|mb| mb.parse().expect("FLV_BENCH_READ_MB is a number"))
+        .unwrap_or(100);
+    let path = env::temp_dir().join(format!("flv-keyframes-bench-read-{}mb.flv", mb));
+    common::write_flv(&path, mb * 1024 * 1024).unwrap();
+    let len = std::fs::metadata(&path).unwrap().len();
+    let patch = runtime.block_on(async {
+        let file = File::open(&path).await.unwrap();
+        generate_patch(file).await.unwrap().patch.unwrap()
+    });
+
+    let mut group = c.benchmark_group("patch_reader");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(len));
+    group.bench_function("direct_sequential", |b| {
+        b.iter(|| {
+            runtime.block_on(async { read_sequential(File::open(&path).await.unwrap()).await })
+        })
+    });
+    group.bench_function("patched_sequential", |b| {
+        b.iter(|| {
+            runtime.block_on(async { read_sequential(open_patched(&path, &patch).await).await })
+        })
+    });
+
+    group.throughput(Throughput::Bytes(RANDOM_READS * READ_SIZE as u64));
+    group.bench_function("patched_random", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut reader = open_patched(&path, &patch).await;
+                let mut buf = [0u8; READ_SIZE];
+                // a fixed LCG, every iteration reads the same positions
+                let mut pos: u64 = 1;
+                let mut total = 0;
+                for _ in 0..RANDOM_READS {
+                    pos = pos
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    reader.seek(SeekFrom::Start(pos % len)).await.unwrap();
+                    total += reader.read(&mut buf).await.unwrap();
+                }
+                total
+            })
+        })
+    });
+    group.finish();
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, bench_patch_reader);
+criterion_main!(benches);
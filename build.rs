@@ -0,0 +1,16 @@
+use vergen::EmitBuilder;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // VERGEN_GIT_SHA for --version, a placeholder outside of a git checkout.
+    // Builds without .git, like the Docker image, pass it in instead.
+    println!("cargo:rerun-if-env-changed=VERGEN_GIT_SHA");
+    match std::env::var("VERGEN_GIT_SHA") {
+        Ok(sha) if !sha.is_empty() => println!("cargo:rustc-env=VERGEN_GIT_SHA={}", sha),
+        _ => {
+            // vergen would take an empty one as it is
+            std::env::remove_var("VERGEN_GIT_SHA");
+            EmitBuilder::builder().git_sha(true).emit()?
+        }
+    }
+    Ok(())
+}
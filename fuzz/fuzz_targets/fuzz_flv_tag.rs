@@ -0,0 +1,11 @@
+#![no_main]
+use flv_keyframes::read_flv_tag;
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// malformed tags must come back as errors, never panic
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data.to_vec());
+    let mut offset = 0;
+    while let Ok(Some(_)) = read_flv_tag(&mut reader, &mut offset) {}
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use flv_keyframes::{Patch, SyncPatchedReader};
+use libfuzzer_sys::fuzz_target;
+use std::io::Read;
+
+// patch files are read from disk, a corrupt one must not panic when loaded
+// or applied
+fuzz_target!(|data: &[u8]| {
+    if let Ok(patch) = bincode::deserialize::<Patch>(data) {
+        let input = vec![0u8; 64];
+        patch.apply_to_bytes(&input);
+        let mut reader = SyncPatchedReader::new(input, patch);
+        let _ = reader.read_to_end(&mut vec![]);
+    }
+});
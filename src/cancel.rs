@@ -0,0 +1,40 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Tells a patch scan to stop, checked between tags. Clones share the same
+/// state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+    /// Cancels once the returned guard is dropped, like when a request goes
+    /// away while its patch is generated.
+    pub fn drop_guard(self) -> DropGuard {
+        DropGuard { token: self }
+    }
+}
+
+/// See `CancellationToken::drop_guard`.
+#[derive(Debug)]
+pub struct DropGuard {
+    token: CancellationToken,
+}
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::FlvError;
+use crate::flv_reader::{read_flv, FlvTagData};
+use flv_codec::FrameType;
+use std::{fmt, io::Read};
+
+/// A problem found by `check_flv`.
+#[derive(Debug)]
+pub enum CheckIssue {
+    /// a tag is earlier than the previous tag of the same type
+    TimestampOutOfOrder {
+        byte_offset: u64,
+        timestamp: i32,
+        previous: i32,
+    },
+    /// the pre tag size after a tag is wrong, usually 0 when it was never written
+    BadPreviousTagSize {
+        byte_offset: u64,
+        expected: u32,
+        found: u32,
+    },
+    NoScriptTag,
+    NoKeyframes,
+    /// the file can't be read any further
+    Fatal(FlvError),
+}
+
+impl CheckIssue {
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, CheckIssue::Fatal(_))
+    }
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckIssue::TimestampOutOfOrder {
+                byte_offset,
+                timestamp,
+                previous,
+            } => write!(
+                f,
+                "tag at {} has timestamp {}ms, before the previous {}ms",
+                byte_offset, timestamp, previous
+            ),
+            CheckIssue::BadPreviousTagSize {
+                byte_offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "tag at {} is followed by pre tag size {}, expected {}",
+                byte_offset, found, expected
+            ),
+            CheckIssue::NoScriptTag => {
+                write!(f, "no script tag, an onMetaData tag will be inserted")
+            }
+            CheckIssue::NoKeyframes => write!(f, "no keyframes found"),
+            CheckIssue::Fatal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// tags read before a fatal issue, if any
+    pub tag_count: usize,
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    pub fn has_fatal(&self) -> bool {
+        self.issues.iter().any(CheckIssue::is_fatal)
+    }
+}
+
+/// Reads the whole file and collects everything that would make patching
+/// fail or produce a useless patch.
+pub fn check_flv<R: Read>(reader: R) -> CheckReport {
+    let mut report = CheckReport::default();
+    let mut has_script = false;
+    let mut has_keyframes = false;
+    let mut last_audio: Option<i32> = None;
+    let mut last_video: Option<i32> = None;
+    for tag in read_flv(reader) {
+        let tag = match tag {
+            Ok(tag) => tag,
+            // the tag is still checked, the size is all that's wrong
+            Err(FlvError::InvalidPreviousTagSize {
+                expected,
+                got,
+                position,
+                tag,
+            }) => {
+                report.issues.push(CheckIssue::BadPreviousTagSize {
+                    byte_offset: position,
+                    expected,
+                    found: got,
+                });
+                *tag
+            }
+            Err(e) => {
+                report.issues.push(CheckIssue::Fatal(e));
+                return report;
+            }
+        };
+        report.tag_count += 1;
+        let (last, timestamp) = match &tag.data {
+            FlvTagData::Audio(audio) => (&mut last_audio, audio.timestamp.value()),
+            FlvTagData::Video(video) => {
+                has_keyframes |= video.frame_type == FrameType::KeyFrame;
+                (&mut last_video, video.timestamp.value())
+            }
+            FlvTagData::Script(_) => {
+                has_script = true;
+                continue;
+            }
+            FlvTagData::Other { .. } => continue,
+        };
+        if let Some(previous) = *last {
+            if timestamp < previous {
+                report.issues.push(CheckIssue::TimestampOutOfOrder {
+                    byte_offset: tag.byte_offset,
+                    timestamp,
+                    previous,
+                });
+            }
+        }
+        *last = Some(timestamp);
+    }
+    if !has_script {
+        report.issues.push(CheckIssue::NoScriptTag);
+    }
+    if !has_keyframes {
+        report.issues.push(CheckIssue::NoKeyframes);
+    }
+    report
+}
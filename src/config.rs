@@ -0,0 +1,287 @@
+use crate::{parse_health_path, parse_origin, Args};
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+use std::{net::IpAddr, path::Path, path::PathBuf};
+
+/// Written by `--config-example`, every key is a flag of the same name with
+/// `-` written as `_`.
+pub const EXAMPLE: &str = r#"# flv-keyframes configuration, flags given on the command line or as FLV_*
+# environment variables win.
+
+# root path to serve, default to "./"
+# root_path = "/srv/flv"
+# directory below root_path the RelativePath of webhook calls is in
+# relative_path = ""
+# prefix of every URL but the webhook's like /media, for serving behind
+# a reverse proxy
+# base_path = ""
+# directory to store patch files in, default to next to the FLV files
+# patch_dir = "/var/cache/flv-keyframes"
+# ignore existing patch files and regenerate them on every request
+# force_regenerate = false
+# serve files as they are, for libraries that are already patched
+# no_patch = false
+# serve files larger than this many bytes without patching them
+# max_file_size = 4294967296
+# serve files other than .flv as they are, like a static file server
+# pass_through_non_flv = false
+# reply with a HTML page listing the files for directories
+# list_directory = false
+# only log invalid headers and pre tag sizes when generating patches
+# lenient = false
+# give up generating a patch after this many milliseconds, replying 503
+# timeout_ms = 30000
+# store patch files compressed with zstd
+# compress_patches = false
+# bytes read from disk per response body chunk
+# chunk_size = 2048
+# patches kept in memory after being read, 0 reads them from disk every time
+# patch_cache_entries = 64
+
+# address and port to listen on
+# bind = "0.0.0.0"
+# port = 3040
+# PEM certificate chain and private key, both are needed for HTTPS
+# tls_cert = "/etc/flv-keyframes/cert.pem"
+# tls_key = "/etc/flv-keyframes/key.pem"
+# origins allowed by CORS, any origin is allowed when empty
+# cors_origin = ["https://example.com"]
+# headers allowed by CORS besides range
+# cors_header = ["x-requested-with"]
+
+# one of trace, debug, info, warn or error
+# log_level = "info"
+# path of the liveness probe
+# health_path = "healthz"
+# query parameter that makes `?<param>=1` reply as an attachment
+# download_param = "download"
+# serve files from this URL instead of root_path
+# remote_base_url = "https://cdn.example.com/flv"
+# generate patches of new and changed FLV files in the background
+# watch = false
+# generate patches on requests without storing them, replying with empty bodies
+# dry_run = false
+# serve Prometheus metrics at /metrics
+# metrics = false
+"#;
+
+/// The flags of `Args` read from a TOML file by `--config`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    root_path: Option<PathBuf>,
+    relative_path: Option<String>,
+    base_path: Option<String>,
+    patch_dir: Option<PathBuf>,
+    force_regenerate: Option<bool>,
+    no_patch: Option<bool>,
+    max_file_size: Option<u64>,
+    pass_through_non_flv: Option<bool>,
+    list_directory: Option<bool>,
+    lenient: Option<bool>,
+    timeout_ms: Option<u64>,
+    compress_patches: Option<bool>,
+    chunk_size: Option<usize>,
+    patch_cache_entries: Option<usize>,
+    port: Option<u16>,
+    cors_origin: Option<Vec<String>>,
+    cors_header: Option<Vec<String>>,
+    bind: Option<IpAddr>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    log_level: Option<String>,
+    health_path: Option<String>,
+    download_param: Option<String>,
+    remote_base_url: Option<String>,
+    watch: Option<bool>,
+    dry_run: Option<bool>,
+    metrics: Option<bool>,
+}
+
+/// Whether the flag `id` was given on the command line or in its `FLV_*`
+/// environment variable, which win over the config file.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+    )
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Sets the fields of `args` that are in the config and not on the
+    /// command line in `matches`.
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<()> {
+        macro_rules! merge {
+            ($($field:ident),*) => {$(
+                if let Some(value) = self.$field {
+                    if !given(matches, stringify!($field)) {
+                        args.$field = value.into();
+                    }
+                }
+            )*};
+        }
+        merge!(
+            root_path,
+            relative_path,
+            base_path,
+            patch_dir,
+            force_regenerate,
+            no_patch,
+            max_file_size,
+            pass_through_non_flv,
+            list_directory,
+            lenient,
+            timeout_ms,
+            compress_patches,
+            chunk_size,
+            patch_cache_entries,
+            port,
+            bind,
+            tls_cert,
+            tls_key,
+            health_path,
+            download_param,
+            remote_base_url,
+            watch,
+            dry_run,
+            metrics
+        );
+        if let Some(origins) = self.cors_origin {
+            if !given(matches, "cors_origin") {
+                args.cors_origin = origins
+                    .iter()
+                    .map(|origin| parse_origin(origin))
+                    .collect::<Result<_>>()?;
+            }
+        }
+        if let Some(headers) = self.cors_header {
+            if !given(matches, "cors_header") {
+                args.cors_header = headers
+                    .iter()
+                    .map(|header| header.parse())
+                    .collect::<Result<_, _>>()
+                    .context("invalid cors_header")?;
+            }
+        }
+        if let Some(level) = self.log_level {
+            if !given(matches, "log_level") {
+                args.log_level = level.parse().context("invalid log_level")?;
+            }
+        }
+        check(args)
+    }
+}
+
+/// The constraints clap checks on the command line, again with the config
+/// values in.
+fn check(args: &Args) -> Result<()> {
+    if args.tls_cert.is_some() != args.tls_key.is_some() {
+        return Err(anyhow!("tls_cert and tls_key go together"));
+    }
+    parse_health_path(&args.health_path)?;
+    if args.chunk_size == 0 {
+        return Err(anyhow!("chunk_size must be at least 1"));
+    }
+    if args.no_patch && args.force_regenerate {
+        return Err(anyhow!("no_patch conflicts with force_regenerate"));
+    }
+    if args.watch && (args.no_patch || args.remote_base_url.is_some()) {
+        return Err(anyhow!("watch conflicts with no_patch and remote_base_url"));
+    }
+    if args.dry_run && args.watch {
+        return Err(anyhow!("dry_run conflicts with watch"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// `Args` from `flags`, with the TOML `config` applied.
+    fn args_with_config(config: &str, flags: &[&str]) -> Result<Args> {
+        let flags = std::iter::once("flv-keyframes").chain(flags.iter().copied());
+        let matches = Args::command().try_get_matches_from(flags)?;
+        let mut args = Args::from_arg_matches(&matches)?;
+        toml::from_str::<Config>(config)?.apply(&mut args, &matches)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn example_has_every_flag() {
+        // the example with every key uncommented
+        let config: String = EXAMPLE
+            .lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .filter(|line| {
+                line.split_once(" = ").is_some_and(|(key, _)| {
+                    key.bytes().all(|b| b.is_ascii_lowercase() || b == b'_')
+                })
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let keys: Vec<&str> = config
+            .lines()
+            .filter_map(|line| Some(line.split_once(" = ")?.0))
+            .collect();
+        for arg in Args::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            if arg.get_long().is_some() && !["config", "config_example"].contains(&id) {
+                assert!(keys.contains(&id), "{} is not in the example", id);
+            }
+        }
+        args_with_config(&config, &[]).unwrap();
+    }
+
+    #[test]
+    fn flags_win_over_the_config() {
+        let config = r#"
+            port = 4000
+            relative_path = "recordings"
+            lenient = true
+            compress_patches = false
+            cors_origin = ["https://example.com"]
+        "#;
+        let args = args_with_config(config, &[]).unwrap();
+        assert_eq!(args.port, 4000);
+        assert_eq!(args.relative_path, "recordings");
+        assert!(args.lenient);
+        assert!(!args.compress_patches);
+        assert_eq!(args.cors_origin, ["https://example.com"]);
+
+        let args = args_with_config(
+            config,
+            &[
+                "--port",
+                "5000",
+                "--relative-path",
+                "",
+                "--compress-patches",
+                "--cors-origin",
+                "https://example.org",
+            ],
+        )
+        .unwrap();
+        assert_eq!(args.port, 5000);
+        assert_eq!(args.relative_path, "");
+        assert!(args.lenient);
+        assert!(args.compress_patches);
+        assert_eq!(args.cors_origin, ["https://example.org"]);
+    }
+
+    #[test]
+    fn config_values_are_checked() {
+        assert!(args_with_config("chunk_size = 0", &[]).is_err());
+        assert!(args_with_config("tls_cert = \"cert.pem\"", &[]).is_err());
+        assert!(args_with_config("watch = true", &["--no-patch"]).is_err());
+        assert!(args_with_config("unknown = 1", &[]).is_err());
+    }
+}
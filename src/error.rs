@@ -0,0 +1,42 @@
+use crate::flv_reader::FlvTag;
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FlvError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("failed to decode FLV tag: {0}")]
+    Codec(#[from] bytecodec::Error),
+    #[error("failed to decode AMF0 data: {0}")]
+    Amf(#[from] amf::error::DecodeError),
+    #[error("invalid FLV: {0}")]
+    InvalidFormat(String),
+    #[error("invalid FLV header: {0}")]
+    InvalidHeader(String),
+    /// the reader is left at the next tag, so reading can go on
+    #[error("pre tag size {got} after the tag at {position} does not match its size {expected}")]
+    InvalidPreviousTagSize {
+        expected: u32,
+        got: u32,
+        position: u64,
+        /// the tag itself, which decoded fine
+        tag: Box<FlvTag>,
+    },
+    #[error("file ends in the middle of a tag")]
+    TruncatedFile,
+    #[error("invalid keyframes: {0}")]
+    InvalidKeyframes(String),
+    #[error("patch file {}: {source}", path.display())]
+    PatchFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("patch generation cancelled")]
+    Cancelled,
+    #[error("file was modified while generating its patch")]
+    FileModifiedDuringPatch,
+}
+
+pub type Result<T> = std::result::Result<T, FlvError>;
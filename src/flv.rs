@@ -1,22 +1,68 @@
 use amf::amf0;
-use anyhow::Result;
-use bytecodec::{
-    io::{IoDecodeExt, IoEncodeExt, ReadBuf},
-    Decode, Encode,
-};
+use bytecodec::{io::IoEncodeExt, Encode};
 use bytes::BufMut;
-use flv_codec::{
-    FileDecoder, FrameType, ScriptDataTag, StreamId, Tag, TagEncoder, Timestamp, VideoTag,
-};
+use flv_codec::{ScriptDataTag, StreamId, Tag, TagEncoder, Timestamp};
+use crate::cancel::CancellationToken;
+use crate::error::{FlvError, Result};
+use crate::flv_reader::{read_flv, read_flv_tag, FlvTag, FlvTagData};
 use crate::keyframes::Keyframes;
-use crate::patch::Patch;
-use std::io::Cursor;
-use tokio::{fs::File, task};
-
-fn has_keyframes(v: amf0::Value) -> bool {
-    match v.try_into_pairs() {
-        Ok(mut iter) => iter.any(|i| i.0 == "keyframes"),
-        Err(_) => false,
+use crate::patch::{Patch, PatchRegion};
+use std::io::{Cursor, Read};
+use tokio::{fs::File, sync::watch, task};
+
+#[derive(Debug, Clone, Default)]
+pub struct FlvMetadata {
+    pub duration: Option<f64>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub framerate: Option<f64>,
+    pub videocodecid: Option<f64>,
+    pub audiocodecid: Option<f64>,
+}
+
+/// The value following the `onMetaData` name of a script tag.
+fn metadata_value(tag: &FlvTag) -> Result<&amf0::Value> {
+    match &tag.data {
+        FlvTagData::Script(script) if script.is_metadata() => Ok(&script.value),
+        FlvTagData::Script(_) => Err(FlvError::InvalidFormat(
+            "script tag is not onMetaData".to_string(),
+        )),
+        _ => Err(FlvError::InvalidFormat("not a script tag".to_string())),
+    }
+}
+
+/// Reads the well known fields of an `onMetaData` script tag, others are skipped.
+pub fn parse_metadata(tag: &FlvTag) -> Result<FlvMetadata> {
+    let data = metadata_value(tag)?;
+    let mut metadata = FlvMetadata::default();
+    let entries = match data {
+        amf0::Value::Object { entries, .. } | amf0::Value::EcmaArray { entries } => entries,
+        _ => return Ok(metadata),
+    };
+    for pair in entries {
+        let field = match pair.key.as_str() {
+            "duration" => &mut metadata.duration,
+            "width" => &mut metadata.width,
+            "height" => &mut metadata.height,
+            "framerate" => &mut metadata.framerate,
+            "videocodecid" => &mut metadata.videocodecid,
+            "audiocodecid" => &mut metadata.audiocodecid,
+            _ => continue,
+        };
+        *field = pair.value.try_as_f64();
+    }
+    Ok(metadata)
+}
+
+/// Whether the metadata already has a valid, non-empty `keyframes` object.
+fn has_keyframes(v: &amf0::Value) -> bool {
+    match v {
+        amf0::Value::Object { entries, .. } | amf0::Value::EcmaArray { entries } => entries
+            .iter()
+            .filter(|pair| pair.key == "keyframes")
+            .filter_map(|pair| Keyframes::from_amf0(&pair.value).ok())
+            .any(|keyframes| !keyframes.is_empty()),
+        _ => false,
     }
 }
 
@@ -31,21 +77,69 @@ fn insert_keyframes(metadata: amf0::Value, keyframes: Keyframes) -> amf0::Value
         )
     }
     let keyframes = std::iter::once(keyframes.into_amf0());
-    let value = metadata
-        .try_into_pairs()
-        .map(|i| amf0::object(i.map(map_amf0).chain(keyframes)));
+    // an existing, possibly wrong, keyframes entry is replaced
+    let value = metadata.try_into_pairs().map(|i| {
+        amf0::object(
+            i.map(map_amf0)
+                .filter(|(key, _)| key != "keyframes")
+                .chain(keyframes),
+        )
+    });
     match value {
         Ok(v) => v,
         Err(v) => v,
     }
 }
 
-fn make_patched(metadata: amf0::Value) -> Vec<u8> {
-    let mut buf = Cursor::new(Vec::<u8>::new());
+/// Encoded size of an AMF0 value, see `amf0::Encoder`.
+fn amf0_size(value: &amf0::Value) -> usize {
+    use amf0::Value;
+
+    let pairs_size = |entries: &[amf::Pair<String, Value>]| -> usize {
+        entries
+            .iter()
+            .map(|pair| 2 + pair.key.len() + amf0_size(&pair.value))
+            .sum::<usize>()
+            + 3
+    };
+    match value {
+        Value::Number(_) => 9,
+        Value::Boolean(_) => 2,
+        Value::String(s) if s.len() <= 0xFFFF => 3 + s.len(),
+        Value::String(s) | Value::XmlDocument(s) => 5 + s.len(),
+        Value::Object {
+            class_name,
+            entries,
+        } => 1 + class_name.as_ref().map_or(0, |name| 2 + name.len()) + pairs_size(entries),
+        Value::Null | Value::Undefined => 1,
+        Value::EcmaArray { entries } => 5 + pairs_size(entries),
+        Value::Array { entries } => 5 + entries.iter().map(amf0_size).sum::<usize>(),
+        Value::Date { .. } => 11,
+        // AMF3 is rare in metadata, not worth sizing by hand
+        Value::AvmPlus(_) => {
+            let mut buf = vec![];
+            value.write_to(&mut buf).map_or(0, |_| buf.len())
+        }
+    }
+}
+
+/// An upper bound of the size of the tag `make_patched` writes, so its
+/// buffers are allocated once.
+fn estimate_patched_size(metadata: &amf0::Value, keyframes: &Keyframes) -> usize {
+    // "filepositions" and "times" arrays of numbers in a "keyframes" object
+    let keyframes_size = (2 + 9) + 1 + (2 + 13 + 5) + (2 + 5 + 5) + 2 * 9 * keyframes.len() + 3;
+    // tag header + "onMetaData" + metadata + pre tag size
+    11 + 13 + amf0_size(metadata) + keyframes_size + 4
+}
+
+fn make_patched(metadata: amf0::Value, keyframes: Keyframes) -> Vec<u8> {
+    let estimate = estimate_patched_size(&metadata, &keyframes);
+    let metadata = insert_keyframes(metadata, keyframes);
+    let mut buf = Cursor::new(Vec::<u8>::with_capacity(estimate));
     amf0::string("onMetaData").write_to(&mut buf).unwrap();
     metadata.write_to(&mut buf).unwrap();
     let mut encoder = TagEncoder::<Vec<u8>>::new();
-    let mut out: Vec<u8> = Vec::new();
+    let mut out: Vec<u8> = Vec::with_capacity(estimate);
     encoder
         .start_encoding(Tag::ScriptData(ScriptDataTag {
             timestamp: Timestamp::new(0),
@@ -62,67 +156,321 @@ fn make_patched(metadata: amf0::Value) -> Vec<u8> {
     out
 }
 
-pub async fn generate_patch(file: File) -> Result<Option<Patch>> {
-    let mut decoder = FileDecoder::new();
-    let mut file = file.into_std().await;
+/// Why `generate_patch` leaves a file as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// the `onMetaData` tag already has non-empty keyframes
+    AlreadyHasKeyframes,
+    /// there is no tag at all, so nowhere to put metadata in front of
+    NoMetadata,
+    /// there is no video keyframe to index
+    NoKeyframes,
+}
+
+/// The patch of a file along with what the scan found.
+#[derive(Debug, Clone, Default)]
+pub struct PatchResult {
+    /// `None` when the file is left as is, see `reason_skipped`
+    pub patch: Option<Patch>,
+    /// the counts stop where the scan does, which is at the `onMetaData`
+    /// tag for `SkipReason::AlreadyHasKeyframes`
+    pub keyframe_count: usize,
+    pub total_tags: usize,
+    /// timestamp of the last tag scanned
+    pub duration_secs: f64,
+    pub reason_skipped: Option<SkipReason>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PatchProgress {
+    pub bytes_scanned: u64,
+    pub total_bytes: u64,
+    pub keyframes_found: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    /// stops the scan between tags, failing with `FlvError::Cancelled`
+    pub cancel: CancellationToken,
+    /// see `FlvReader::lenient`
+    pub lenient: bool,
+}
+
+/// Collects the keyframes of a whole file along with its metadata, which is
+/// left empty without an `onMetaData` tag. This is blocking.
+pub fn read_keyframes<R: Read>(reader: R) -> Result<(Keyframes, FlvMetadata)> {
+    let mut keyframes = Keyframes::new();
+    let mut metadata = None;
+    for tag in read_flv(reader) {
+        let tag = tag?;
+        match &tag.data {
+            FlvTagData::Video(_) if tag.is_keyframe() => {
+                keyframes.add(tag.byte_offset, tag.timestamp_as_duration().as_secs_f64());
+            }
+            FlvTagData::Script(_) if metadata.is_none() => {
+                metadata = parse_metadata(&tag).ok();
+            }
+            _ => {}
+        }
+    }
+    keyframes.sort();
+    Ok((keyframes, metadata.unwrap_or_default()))
+}
+
+/// Reads back the keyframes and metadata a patch from `generate_patch`
+/// inserts. The filepositions are those of the patched file.
+pub fn read_patch_metadata(patch: &Patch) -> Result<Option<(Keyframes, FlvMetadata)>> {
+    for region in patch.regions() {
+        let tag = match read_flv_tag(&mut &region.patched[..], &mut 0)? {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let data = match &tag.data {
+            FlvTagData::Script(_) => metadata_value(&tag)?,
+            _ => continue,
+        };
+        let keyframes = match data {
+            amf0::Value::Object { entries, .. } | amf0::Value::EcmaArray { entries } => entries
+                .iter()
+                .find(|pair| pair.key == "keyframes")
+                .map(|pair| Keyframes::from_amf0(&pair.value)),
+            _ => None,
+        };
+        if let Some(keyframes) = keyframes {
+            return Ok(Some((keyframes?, parse_metadata(&tag)?)));
+        }
+    }
+    Ok(None)
+}
+
+pub async fn generate_patch(file: File) -> Result<PatchResult> {
+    generate_patch_cancellable(file, CancellationToken::new()).await
+}
+
+/// Same as `generate_patch`, reporting progress after every tag.
+pub async fn generate_patch_with_progress(
+    file: File,
+    progress: watch::Sender<PatchProgress>,
+) -> Result<PatchResult> {
+    scan_file(file, progress, PatchOptions::default()).await
+}
+
+/// Same as `generate_patch`, failing with `FlvError::Cancelled` once `cancel` fires.
+/// The token is checked between tags.
+pub async fn generate_patch_cancellable(
+    file: File,
+    cancel: CancellationToken,
+) -> Result<PatchResult> {
+    generate_patch_with_options(
+        file,
+        PatchOptions {
+            cancel,
+            ..PatchOptions::default()
+        },
+    )
+    .await
+}
+
+pub async fn generate_patch_with_options(file: File, options: PatchOptions) -> Result<PatchResult> {
+    let (progress, _) = watch::channel(PatchProgress::default());
+    scan_file(file, progress, options).await
+}
+
+/// Scans `file`, failing with `FlvError::FileModifiedDuringPatch` if its size
+/// or modification time changed meanwhile, like a spool of a live stream
+/// being written. That error wins over the scan's own, which a half written
+/// tag can cause.
+async fn scan_file(
+    file: File,
+    progress: watch::Sender<PatchProgress>,
+    options: PatchOptions,
+) -> Result<PatchResult> {
+    let reader = file.try_clone().await?.into_std().await;
+    scan_file_with(file, reader, progress, options).await
+}
+
+/// `scan_file` reading `file` through `reader`.
+async fn scan_file_with<R: Read + Send + 'static>(
+    file: File,
+    reader: R,
+    progress: watch::Sender<PatchProgress>,
+    options: PatchOptions,
+) -> Result<PatchResult> {
+    let before = file.metadata().await?;
+    let result = scan(reader, before.len(), progress, options).await;
+    let after = file.metadata().await?;
+    if after.len() != before.len() || after.modified()? != before.modified()? {
+        return Err(FlvError::FileModifiedDuringPatch);
+    }
+    result
+}
+
+/// Same as `generate_patch_with_options` for a file that is not on disk,
+/// like a HTTP response. `reader` is read on a blocking thread and
+/// `total_bytes` is only used for progress.
+pub async fn generate_patch_from_reader<R: Read + Send + 'static>(
+    reader: R,
+    total_bytes: u64,
+    options: PatchOptions,
+) -> Result<PatchResult> {
+    let (progress, _) = watch::channel(PatchProgress::default());
+    scan(reader, total_bytes, progress, options).await
+}
+
+async fn scan<R: Read + Send + 'static>(
+    file: R,
+    total_bytes: u64,
+    progress: watch::Sender<PatchProgress>,
+    options: PatchOptions,
+) -> Result<PatchResult> {
     let mut keyframes = Keyframes::new();
-    let mut offset: u64 = 13; // flv header
     let mut metadata_offset: u64 = 0;
     let mut metadata_size: u64 = 0;
     let mut metadata: Option<amf0::Value> = None;
+    let mut first_tag_offset: Option<u64> = None;
+    let mut result = PatchResult::default();
     task::spawn_blocking(move || {
-        let mut buf = ReadBuf::new(vec![0; 4096]);
-        while !buf.stream_state().is_eos() {
-            buf.fill(&mut file)?;
-            decoder.decode_from_read_buf(&mut buf)?;
-            if decoder.is_idle() {
-                let tag = decoder.finish_decoding()?;
-                let tag_size = tag.tag_size() as u64;
-                match tag {
-                    Tag::Audio(_) => {}
-                    Tag::Video(VideoTag {
-                        timestamp,
-                        frame_type,
-                        ..
-                    }) => {
-                        if frame_type == FrameType::KeyFrame {
-                            keyframes.add(offset, (timestamp.value() as f64) / 1000f64);
-                        }
-                    }
-                    Tag::ScriptData(tag @ ScriptDataTag { .. }) => {
-                        let data = &mut &tag.data[..];
-                        let mut amf_decoder = amf0::Decoder::new(data);
-                        let data = match amf_decoder.decode()? {
-                            amf0::Value::String(name) if name == "onMetaData" => {
-                                amf_decoder.decode()?
-                            }
-                            _ => return Err(anyhow::anyhow!("InvalidData")),
-                        };
-                        metadata_offset = offset;
-                        metadata_size = tag.tag_size() as u64 + 4;
-                        let has_keyframes = has_keyframes(data.clone());
-                        if has_keyframes {
-                            return Ok(None);
-                        }
-                        metadata = Some(data);
-                    }
-                };
-                // data + pre tag size
-                offset += tag_size + 4;
+        let skip = |mut result: PatchResult, keyframes: &Keyframes, reason| {
+            result.keyframe_count = keyframes.len();
+            result.reason_skipped = Some(reason);
+            Ok(result)
+        };
+        for tag in read_flv(file).lenient(options.lenient).with_offsets() {
+            if options.cancel.is_cancelled() {
+                return Err(FlvError::Cancelled);
+            }
+            let (byte_offset, tag) = tag?;
+            let tag_size = tag.tag_size;
+            first_tag_offset.get_or_insert(byte_offset);
+            result.total_tags += 1;
+            result.duration_secs = tag.timestamp_as_duration().as_secs_f64();
+            if tag.is_keyframe() {
+                keyframes.add(byte_offset, result.duration_secs);
             }
+            match tag.data {
+                // other script tags like onCuePoint stay where they are
+                FlvTagData::Script(script) if script.is_metadata() => {
+                    metadata_offset = byte_offset;
+                    metadata_size = tag_size as u64 + 4;
+                    if has_keyframes(&script.value) {
+                        return skip(result, &keyframes, SkipReason::AlreadyHasKeyframes);
+                    }
+                    metadata = Some(script.value);
+                }
+                _ => {}
+            };
+            // nobody listening is fine
+            let _ = progress.broadcast(PatchProgress {
+                bytes_scanned: byte_offset + tag_size as u64 + 4,
+                total_bytes,
+                keyframes_found: keyframes.len(),
+            });
+        }
+        let m = match metadata {
+            Some(m) => m,
+            // without a script tag a new one goes in front of the first tag
+            None => match first_tag_offset {
+                Some(offset) => {
+                    metadata_offset = offset;
+                    metadata_size = 0;
+                    amf0::object(std::iter::empty::<(String, amf0::Value)>())
+                }
+                None => return skip(result, &keyframes, SkipReason::NoMetadata),
+            },
+        };
+        // some muxers write tags out of order, players need increasing times
+        keyframes.sort();
+        // players don't cope well with empty filepositions, keep the file as is
+        if keyframes.is_empty() {
+            tracing::warn!("no keyframes found, nothing to patch");
+            return skip(result, &keyframes, SkipReason::NoKeyframes);
         }
-        Result::Ok(metadata.map(|m| {
-            let patched_len =
-                make_patched(insert_keyframes(m.clone(), keyframes.clone())).len() as i64;
-            keyframes.offset = (patched_len - metadata_size as i64) as f64;
-            let patched = make_patched(insert_keyframes(m, keyframes));
-            Patch {
-                origin_pos: metadata_offset,
-                origin_size: metadata_size,
-                patched,
+        let patched_len = make_patched(m.clone(), keyframes.clone()).len() as i64;
+        let delta = patched_len - metadata_size as i64;
+        // only keyframes behind the replaced script tag move, some encoders
+        // write it after the first video tag
+        let mut shifted = Keyframes::new();
+        for (position, time) in keyframes.iter() {
+            if position >= metadata_offset {
+                shifted.add((position as i64 + delta) as u64, time);
+            } else {
+                shifted.add(position, time);
             }
-        }))
+        }
+        result.keyframe_count = shifted.len();
+        let patched = make_patched(m, shifted);
+        result.patch = Some(Patch::new(vec![PatchRegion {
+            origin_pos: metadata_offset,
+            origin_size: metadata_size,
+            patched,
+        }])?);
+        Ok(result)
     })
     .await
     .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    /// Blocks the first read until `gate` is dropped, after telling `blocked`.
+    struct GatedReader<R> {
+        inner: R,
+        blocked: Option<mpsc::Sender<()>>,
+        gate: mpsc::Receiver<()>,
+    }
+
+    impl<R: Read> Read for GatedReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if let Some(blocked) = self.blocked.take() {
+                let _ = blocked.send(());
+                let _ = self.gate.recv();
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_file_grows_during_the_scan() {
+        let path = std::env::temp_dir().join(format!("growing_{}.flv", std::process::id()));
+        // a header and a video keyframe
+        let mut input = b"FLV\x01\x01\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+        input.extend_from_slice(b"\x09\x00\x00\x05\x00\x00\x00\x00\x00\x00\x00");
+        input.extend_from_slice(b"\x17\x01\x00\x00\x00\x00\x00\x00\x10");
+        std::fs::write(&path, &input).unwrap();
+        let file = File::open(&path).await.unwrap();
+        let (blocked, is_blocked) = mpsc::channel();
+        let (open, gate) = mpsc::channel();
+        let reader = GatedReader {
+            inner: file.try_clone().await.unwrap().into_std().await,
+            blocked: Some(blocked),
+            gate,
+        };
+        let (progress, _) = watch::channel(PatchProgress::default());
+        let scan = tokio::spawn(scan_file_with(
+            file,
+            reader,
+            progress,
+            PatchOptions::default(),
+        ));
+
+        // half a tag written by a recorder once the scan is under way
+        task::spawn_blocking(move || is_blocked.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[9])
+            .unwrap();
+        drop(open);
+        let result = scan.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(FlvError::FileModifiedDuringPatch)));
+    }
+}
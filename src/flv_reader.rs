@@ -0,0 +1,540 @@
+use crate::error::{FlvError, Result};
+use amf::amf0;
+use bytecodec::DecodeExt;
+use flv_codec::{AudioTag, FrameType, ScriptDataTag, Tag, TagDecoder, Timestamp, VideoTag};
+use std::{
+    fmt,
+    io::{self, BufReader, Read},
+    time::Duration,
+};
+
+const TAG_TYPE_AUDIO: u8 = 8;
+const TAG_TYPE_VIDEO: u8 = 9;
+const TAG_TYPE_SCRIPT_DATA: u8 = 18;
+
+const FLAG_AUDIO: u8 = 0b0000_0100;
+const FLAG_VIDEO: u8 = 0b0000_0001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlvHeader {
+    pub version: u8,
+    pub has_audio: bool,
+    pub has_video: bool,
+    /// bits of the flags byte besides audio and video, always 0 in valid files
+    pub reserved_flags: u8,
+    /// size of the header, where the first pre tag size starts
+    pub data_offset: u32,
+}
+
+impl FlvHeader {
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(FlvError::InvalidHeader(reason));
+        if self.version != 1 {
+            return invalid(format!("unknown version {}", self.version));
+        }
+        if self.data_offset < 9 {
+            return invalid(format!("data offset {} is too small", self.data_offset));
+        }
+        if self.reserved_flags != 0 {
+            return invalid(format!(
+                "reserved flags {:#04x} are set",
+                self.reserved_flags
+            ));
+        }
+        if !self.has_audio && !self.has_video {
+            return invalid("neither audio nor video".to_string());
+        }
+        Ok(())
+    }
+    /// The header as `read_flv_header` reads it: the 9 bytes header, zeros
+    /// up to `data_offset` and the first pre tag size.
+    pub fn write_to_bytes(&self) -> Vec<u8> {
+        let mut flags = self.reserved_flags;
+        if self.has_audio {
+            flags |= FLAG_AUDIO;
+        }
+        if self.has_video {
+            flags |= FLAG_VIDEO;
+        }
+        let mut bytes = b"FLV".to_vec();
+        bytes.push(self.version);
+        bytes.push(flags);
+        bytes.extend_from_slice(&self.data_offset.to_be_bytes());
+        bytes.resize(self.data_offset.max(9) as usize + 4, 0);
+        bytes
+    }
+}
+
+impl From<FlvHeader> for Vec<u8> {
+    fn from(header: FlvHeader) -> Vec<u8> {
+        header.write_to_bytes()
+    }
+}
+
+impl fmt::Display for FlvHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FLV v{} [audio={}, video={}, data_offset={}]",
+            self.version, self.has_audio, self.has_video, self.data_offset
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FlvTagData {
+    Audio(AudioTag),
+    Video(VideoTag),
+    Script(ScriptData),
+    /// a tag type unknown to `flv_codec`, or a script tag other than
+    /// `onMetaData` that does not decode, its data is kept undecoded
+    Other {
+        tag_type: u8,
+        timestamp: Timestamp,
+        data: Vec<u8>,
+    },
+}
+
+/// A script tag decoded into its name and AMF0 value.
+#[derive(Debug, Clone)]
+pub struct ScriptData {
+    pub timestamp: Timestamp,
+    /// like `onMetaData` or `onCuePoint`
+    pub name: String,
+    pub value: amf0::Value,
+    /// the AMF0 encoded name and value as found in the file
+    #[cfg(feature = "raw-script")]
+    pub data: Vec<u8>,
+}
+
+/// `onMetaData` as the AMF0 string starting its script tag.
+const METADATA_NAME: &[u8] = b"\x02\x00\x0aonMetaData";
+
+/// Deepest nesting of AMF0 objects and arrays accepted in script tags.
+const MAX_AMF0_DEPTH: usize = 16;
+
+/// Walks one AMF0 value without decoding it. `amf` allocates the lengths it
+/// reads up front and recurses without limit, so lengths past the end of the
+/// data, deep nesting, references and AMF3 values are rejected before it
+/// gets to see them.
+fn check_amf0(data: &mut &[u8], depth: usize) -> Result<()> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+        if data.len() < len {
+            return Err(FlvError::InvalidFormat(
+                "script data ends in the middle of a value".to_string(),
+            ));
+        }
+        let (head, rest) = data.split_at(len);
+        *data = rest;
+        Ok(head)
+    }
+    fn take_len(data: &mut &[u8], width: usize) -> Result<usize> {
+        let len = take(data, width)?
+            .iter()
+            .fold(0, |len, &b| len << 8 | b as usize);
+        Ok(len)
+    }
+    fn check_pairs(data: &mut &[u8], depth: usize) -> Result<()> {
+        loop {
+            let key_len = take_len(data, 2)?;
+            take(data, key_len)?;
+            if key_len == 0 && data.first() == Some(&0x09) {
+                *data = &data[1..];
+                return Ok(());
+            }
+            check_amf0(data, depth)?;
+        }
+    }
+
+    if depth > MAX_AMF0_DEPTH {
+        return Err(FlvError::InvalidFormat(
+            "script data is nested too deep".to_string(),
+        ));
+    }
+    let depth = depth + 1;
+    match take(data, 1)?[0] {
+        // number
+        0x00 => drop(take(data, 8)?),
+        // boolean
+        0x01 => drop(take(data, 1)?),
+        // string
+        0x02 => drop(take_len(data, 2).and_then(|len| take(data, len))?),
+        // object
+        0x03 => check_pairs(data, depth)?,
+        // null, undefined
+        0x05 | 0x06 => {}
+        // ECMA array, its count is only a hint
+        0x08 => {
+            take(data, 4)?;
+            check_pairs(data, depth)?;
+        }
+        // strict array
+        0x0a => {
+            let count = take_len(data, 4)?;
+            // every value takes at least a byte
+            if count > data.len() {
+                return Err(FlvError::InvalidFormat(
+                    "script data ends in the middle of a value".to_string(),
+                ));
+            }
+            for _ in 0..count {
+                check_amf0(data, depth)?;
+            }
+        }
+        // date
+        0x0b => drop(take(data, 10)?),
+        // long string, XML document
+        0x0c | 0x0f => drop(take_len(data, 4).and_then(|len| take(data, len))?),
+        // typed object
+        0x10 => {
+            take_len(data, 2).and_then(|len| take(data, len))?;
+            check_pairs(data, depth)?;
+        }
+        marker => {
+            return Err(FlvError::InvalidFormat(format!(
+                "unsupported AMF0 marker {:#04x} in script data",
+                marker
+            )))
+        }
+    }
+    Ok(())
+}
+
+impl ScriptData {
+    pub fn is_metadata(&self) -> bool {
+        self.name == "onMetaData"
+    }
+    /// Decodes the name and value of a script tag.
+    fn decode(tag: &ScriptDataTag) -> Result<ScriptData> {
+        let mut data = &tag.data[..];
+        check_amf0(&mut data, 0)?;
+        check_amf0(&mut data, 0)?;
+        let mut decoder = amf0::Decoder::new(&tag.data[..]);
+        let name = match decoder.decode()? {
+            amf0::Value::String(name) => name,
+            _ => {
+                return Err(FlvError::InvalidFormat(
+                    "script tag name is not a string".to_string(),
+                ))
+            }
+        };
+        let value = decoder.decode()?;
+        Ok(ScriptData {
+            timestamp: tag.timestamp,
+            name,
+            value,
+            #[cfg(feature = "raw-script")]
+            data: tag.data.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FlvTag {
+    /// position of the tag header in the file
+    pub byte_offset: u64,
+    /// tag header + data, without the trailing pre tag size
+    pub tag_size: u32,
+    pub data: FlvTagData,
+    /// the tag body as read from the file
+    #[cfg(feature = "raw-data")]
+    pub raw_data: Option<Vec<u8>>,
+}
+
+impl FlvTagData {
+    /// The tag type byte, 8 for audio, 9 for video and 18 for script data.
+    pub fn tag_type(&self) -> u8 {
+        match self {
+            FlvTagData::Audio(_) => TAG_TYPE_AUDIO,
+            FlvTagData::Video(_) => TAG_TYPE_VIDEO,
+            FlvTagData::Script(_) => TAG_TYPE_SCRIPT_DATA,
+            FlvTagData::Other { tag_type, .. } => *tag_type,
+        }
+    }
+    pub fn is_audio(&self) -> bool {
+        matches!(self, FlvTagData::Audio(_))
+    }
+    pub fn is_video(&self) -> bool {
+        matches!(self, FlvTagData::Video(_))
+    }
+    pub fn is_script(&self) -> bool {
+        matches!(self, FlvTagData::Script(_))
+    }
+    pub fn timestamp(&self) -> Timestamp {
+        match self {
+            FlvTagData::Audio(tag) => tag.timestamp,
+            FlvTagData::Video(tag) => tag.timestamp,
+            FlvTagData::Script(tag) => tag.timestamp,
+            FlvTagData::Other { timestamp, .. } => *timestamp,
+        }
+    }
+    /// A video tag of a key frame, generated key frames don't count.
+    pub fn is_keyframe(&self) -> bool {
+        matches!(self, FlvTagData::Video(video) if video.frame_type == FrameType::KeyFrame)
+    }
+}
+
+impl fmt::Display for FlvTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tag={} ts={}ms size={}",
+            self.data.tag_type(),
+            self.timestamp_ms(),
+            self.tag_size
+        )
+    }
+}
+
+impl FlvTag {
+    pub fn is_audio(&self) -> bool {
+        self.data.is_audio()
+    }
+    pub fn is_video(&self) -> bool {
+        self.data.is_video()
+    }
+    pub fn is_script(&self) -> bool {
+        self.data.is_script()
+    }
+    pub fn is_keyframe(&self) -> bool {
+        self.data.is_keyframe()
+    }
+    /// Timestamp of the tag in milliseconds.
+    pub fn timestamp_ms(&self) -> u32 {
+        self.data.timestamp().value() as u32
+    }
+    pub fn timestamp_as_duration(&self) -> Duration {
+        Duration::from_millis(self.timestamp_ms() as u64)
+    }
+    /// The tag body as read from the file, only kept with the `raw-data` feature.
+    pub fn data_as_bytes(&self) -> Option<&[u8]> {
+        #[cfg(feature = "raw-data")]
+        return self.raw_data.as_deref();
+        #[cfg(not(feature = "raw-data"))]
+        None
+    }
+}
+
+pub struct FlvReader<R> {
+    reader: BufReader<R>,
+    header: Option<FlvHeader>,
+    offset: u64,
+    lenient: bool,
+}
+
+impl<R> FlvReader<R> {
+    /// Logs a pre tag size not matching its tag or a header failing
+    /// `FlvHeader::validate` instead of failing, some muxers never fill
+    /// them in right.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+    /// Yields every tag along with the position of its header in the file.
+    pub fn with_offsets(self) -> FlvOffsetReader<R> {
+        FlvOffsetReader { inner: self }
+    }
+}
+
+impl<R: Read> Iterator for FlvReader<R> {
+    type Item = Result<FlvTag>;
+
+    fn next(&mut self) -> Option<Result<FlvTag>> {
+        if self.header.is_none() {
+            match read_header(&mut self.reader, &mut self.offset, self.lenient) {
+                Ok(header) => self.header = Some(header),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        read_tag(&mut self.reader, &mut self.offset, self.lenient).transpose()
+    }
+}
+
+/// A `FlvReader` yielding `(offset, tag)`, `offset` is where the 11 bytes
+/// tag header starts, the same as `FlvTag::byte_offset`.
+pub struct FlvOffsetReader<R> {
+    inner: FlvReader<R>,
+}
+
+impl<R: Read> Iterator for FlvOffsetReader<R> {
+    type Item = Result<(u64, FlvTag)>;
+
+    fn next(&mut self) -> Option<Result<(u64, FlvTag)>> {
+        Some(self.inner.next()?.map(|tag| (tag.byte_offset, tag)))
+    }
+}
+
+/// Maps the frame type nibble (upper 4 bits of a video tag's first byte).
+/// `FrameType` is `flv_codec`'s, so this can't be `FrameType::from_u8` or
+/// `TryFrom<u8>` for it.
+pub fn frame_type_from_u8(v: u8) -> Result<FrameType> {
+    Ok(match v {
+        1 => FrameType::KeyFrame,
+        2 => FrameType::InterFrame,
+        3 => FrameType::DisposableInterFrame,
+        4 => FrameType::GeneratedKeyFrame,
+        5 => FrameType::VideoInfoOrCommandFrame,
+        _ => {
+            return Err(FlvError::InvalidFormat(format!(
+                "unknown video frame type {}",
+                v
+            )))
+        }
+    })
+}
+
+/// Lowercase name of the frame type, `FrameType` is `flv_codec`'s so it
+/// can't implement `Display` here.
+pub fn frame_type_name(frame_type: FrameType) -> &'static str {
+    match frame_type {
+        FrameType::KeyFrame => "keyframe",
+        FrameType::InterFrame => "interframe",
+        FrameType::DisposableInterFrame => "disposableinterframe",
+        FrameType::GeneratedKeyFrame => "generatedkeyframe",
+        FrameType::VideoInfoOrCommandFrame => "videoinfoorcommandframe",
+    }
+}
+
+/// Reads the FLV header and the first pre tag size, `offset` is advanced by
+/// every byte consumed from `reader`.
+pub fn read_flv_header<R: Read>(reader: &mut R, offset: &mut u64) -> Result<FlvHeader> {
+    read_header(reader, offset, false)
+}
+
+fn read_header<R: Read>(reader: &mut R, offset: &mut u64, lenient: bool) -> Result<FlvHeader> {
+    let mut buf = [0u8; 9];
+    reader.read_exact(&mut buf)?;
+    if &buf[..3] != b"FLV" {
+        return Err(FlvError::InvalidFormat("not a FLV file".to_string()));
+    }
+    let header = FlvHeader {
+        version: buf[3],
+        has_audio: buf[4] & FLAG_AUDIO != 0,
+        has_video: buf[4] & FLAG_VIDEO != 0,
+        reserved_flags: buf[4] & !(FLAG_AUDIO | FLAG_VIDEO),
+        data_offset: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]),
+    };
+    if let Err(e) = header.validate() {
+        // the tags can't be found with a data offset inside the header
+        if !lenient || header.data_offset < 9 {
+            return Err(e);
+        }
+        tracing::warn!("{}", e);
+    }
+    io::copy(
+        &mut reader.by_ref().take(header.data_offset as u64 - 9),
+        &mut io::sink(),
+    )?;
+    reader.read_exact(&mut [0u8; 4])?;
+    *offset += header.data_offset as u64 + 4;
+    Ok(header)
+}
+
+/// `read_exact`, running out of data is a truncated file.
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => FlvError::TruncatedFile,
+        _ => e.into(),
+    })
+}
+
+/// Reads the next tag, `offset` is advanced by every byte consumed from `reader`.
+///
+/// Returns `None` at the end of the file.
+pub fn read_flv_tag<R: Read>(reader: &mut R, offset: &mut u64) -> Result<Option<FlvTag>> {
+    read_tag(reader, offset, false)
+}
+
+fn read_tag<R: Read>(reader: &mut R, offset: &mut u64, lenient: bool) -> Result<Option<FlvTag>> {
+    let mut header = [0u8; 11];
+    if reader.read(&mut header[..1])? == 0 {
+        return Ok(None);
+    }
+    read_exact(reader, &mut header[1..])?;
+    let tag_type = header[0];
+    let data_size = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+    let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+
+    let mut bytes = header.to_vec();
+    reader
+        .by_ref()
+        .take(data_size as u64)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() != header.len() + data_size as usize {
+        return Err(FlvError::TruncatedFile);
+    }
+    let mut pre_tag_size = [0u8; 4];
+    read_exact(reader, &mut pre_tag_size)?;
+    let tag_size = bytes.len() as u32;
+    let byte_offset = *offset;
+    // data + pre tag size, the whole tag is consumed even if it is invalid
+    *offset += tag_size as u64 + 4;
+    let got = u32::from_be_bytes(pre_tag_size);
+    if got != tag_size && lenient {
+        tracing::warn!(
+            "pre tag size {} after the tag at {} does not match its size {}",
+            got,
+            byte_offset,
+            tag_size
+        );
+    }
+
+    #[cfg(feature = "raw-data")]
+    let raw_data = Some(bytes[header.len()..].to_vec());
+    let data = match tag_type {
+        TAG_TYPE_AUDIO | TAG_TYPE_VIDEO | TAG_TYPE_SCRIPT_DATA => {
+            match TagDecoder::new().decode_from_bytes(&bytes)? {
+                Tag::Audio(tag) => FlvTagData::Audio(tag),
+                Tag::Video(tag) => FlvTagData::Video(tag),
+                Tag::ScriptData(tag) => match ScriptData::decode(&tag) {
+                    Ok(script) => FlvTagData::Script(script),
+                    // only metadata matters for patching, cue points and
+                    // the like are kept as they are
+                    Err(e) if !tag.data.starts_with(METADATA_NAME) => {
+                        tracing::debug!("undecodable script tag at {}: {}", byte_offset, e);
+                        FlvTagData::Other {
+                            tag_type,
+                            timestamp: tag.timestamp,
+                            data: tag.data,
+                        }
+                    }
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        _ => FlvTagData::Other {
+            tag_type,
+            timestamp: Timestamp::new(timestamp as i32),
+            data: bytes.split_off(header.len()),
+        },
+    };
+    let tag = FlvTag {
+        byte_offset,
+        tag_size,
+        data,
+        #[cfg(feature = "raw-data")]
+        raw_data,
+    };
+    if got != tag_size && !lenient {
+        return Err(FlvError::InvalidPreviousTagSize {
+            expected: tag_size,
+            got,
+            position: byte_offset,
+            tag: Box::new(tag),
+        });
+    }
+    Ok(Some(tag))
+}
+
+pub fn read_flv_with_offsets<R: Read>(reader: R) -> FlvOffsetReader<R> {
+    read_flv(reader).with_offsets()
+}
+
+pub fn read_flv<R: Read>(reader: R) -> FlvReader<R> {
+    FlvReader {
+        reader: BufReader::new(reader),
+        header: None,
+        offset: 0,
+        lenient: false,
+    }
+}
@@ -1,27 +1,275 @@
-#[derive(Debug, Clone)]
+use crate::error::{FlvError, Result};
+
+#[derive(Debug, Clone, Default)]
 pub struct Keyframes {
     filepositions: Vec<f64>,
     times: Vec<f64>,
-    pub offset: f64,
+    /// Added to every fileposition by `into_amf0` and `to_json`, 0 by
+    /// default, the other methods give the positions found in the file. Set
+    /// it before writing keyframes collected from a file into a copy whose
+    /// metadata tag changed size, to the new tag size minus the old one,
+    /// which is only right when the metadata comes before every keyframe.
+    /// `generate_patch` moves the positions itself and leaves it at 0.
+    pub fileposition_adjustment: f64,
 }
 impl Keyframes {
     pub fn new() -> Keyframes {
         Keyframes {
             filepositions: vec![],
             times: vec![],
-            offset: 0f64,
+            fileposition_adjustment: 0f64,
         }
     }
+    /// Reads a `keyframes` object like the one written by `into_amf0`, the
+    /// filepositions are taken as is without an adjustment.
+    pub fn from_amf0(value: &amf::amf0::Value) -> Result<Keyframes> {
+        use amf::amf0::Value;
+
+        let entries = match value {
+            Value::Object { entries, .. } | Value::EcmaArray { entries } => entries,
+            _ => {
+                return Err(FlvError::InvalidKeyframes(
+                    "keyframes is not an object".to_string(),
+                ))
+            }
+        };
+        let numbers = |key: &str| -> Result<Vec<f64>> {
+            match entries
+                .iter()
+                .find(|pair| pair.key == key)
+                .map(|pair| &pair.value)
+            {
+                Some(Value::Array { entries }) => entries
+                    .iter()
+                    .map(|v| {
+                        v.try_as_f64().ok_or_else(|| {
+                            FlvError::InvalidKeyframes(format!("{} is not a number", key))
+                        })
+                    })
+                    .collect(),
+                Some(_) => Err(FlvError::InvalidKeyframes(format!(
+                    "{} is not an array",
+                    key
+                ))),
+                None => Err(FlvError::InvalidKeyframes(format!("{} is missing", key))),
+            }
+        };
+        let filepositions = numbers("filepositions")?;
+        let times = numbers("times")?;
+        if filepositions.len() != times.len() {
+            return Err(FlvError::InvalidKeyframes(format!(
+                "{} filepositions but {} times",
+                filepositions.len(),
+                times.len()
+            )));
+        }
+        if times.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(FlvError::InvalidKeyframes(
+                "times are not increasing".to_string(),
+            ));
+        }
+        Ok(Keyframes {
+            filepositions,
+            times,
+            fileposition_adjustment: 0f64,
+        })
+    }
+    /// Appends a keyframe without looking at the order, call `sort` once
+    /// everything is in if tags may come out of order.
     pub fn add(&mut self, offset: u64, time: f64) {
         self.filepositions.push(offset as f64);
         self.times.push(time);
     }
+    /// Appends a keyframe, failing if `time` is before the last one.
+    pub fn try_add(&mut self, offset: u64, time: f64) -> Result<()> {
+        match self.times.last() {
+            Some(&last) if time < last => Err(FlvError::InvalidKeyframes(format!(
+                "time {} is before the last keyframe at {}",
+                time, last
+            ))),
+            _ => {
+                self.add(offset, time);
+                Ok(())
+            }
+        }
+    }
+    /// Orders the keyframes by time, keeping the file order of equal times.
+    pub fn sort(&mut self) {
+        let mut pairs: Vec<(f64, f64)> = self
+            .filepositions
+            .iter()
+            .copied()
+            .zip(self.times.iter().copied())
+            .collect();
+        pairs.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let (filepositions, times) = pairs.into_iter().unzip();
+        self.filepositions = filepositions;
+        self.times = times;
+    }
+    /// Number of keyframes, `filepositions` and `times` always have this length.
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+    /// The first `(fileposition, time)` pair, without the adjustment.
+    pub fn first(&self) -> Option<(u64, f64)> {
+        self.iter().next()
+    }
+    /// The last `(fileposition, time)` pair, without the adjustment.
+    pub fn last(&self) -> Option<(u64, f64)> {
+        Some(self.get(self.len().checked_sub(1)?))
+    }
+    /// Time from the first keyframe to the last one.
+    pub fn duration(&self) -> Option<f64> {
+        Some(self.last()?.1 - self.first()?.1)
+    }
+    /// Mean time between consecutive keyframes, `None` with less than 2.
+    pub fn average_interval(&self) -> Option<f64> {
+        match self.len() {
+            0 | 1 => None,
+            len => Some(self.duration()? / (len - 1) as f64),
+        }
+    }
+    /// At most `target_count` keyframes picked evenly, keeping the first and
+    /// the last one. Players get slow with thousands of them in the metadata.
+    pub fn downsample(&self, target_count: usize) -> Keyframes {
+        let len = self.len();
+        if target_count >= len {
+            return self.clone();
+        }
+        let indexes: Vec<usize> = match target_count {
+            0 => vec![],
+            1 => vec![0],
+            n => (0..n).map(|i| i * (len - 1) / (n - 1)).collect(),
+        };
+        Keyframes {
+            filepositions: indexes.iter().map(|&i| self.filepositions[i]).collect(),
+            times: indexes.iter().map(|&i| self.times[i]).collect(),
+            fileposition_adjustment: self.fileposition_adjustment,
+        }
+    }
+    /// Sets `fileposition_adjustment`.
+    pub fn with_fileposition_adjustment(mut self, adjustment: f64) -> Keyframes {
+        self.fileposition_adjustment = adjustment;
+        self
+    }
+    /// The keyframes with `start <= time <= end`. The adjustment is reset to 0
+    /// and has to be set again if needed.
+    pub fn filter_by_time_range(&self, start: f64, end: f64) -> Keyframes {
+        let (filepositions, times) = self
+            .filepositions
+            .iter()
+            .zip(&self.times)
+            .filter(|&(_, &time)| start <= time && time <= end)
+            .unzip();
+        Keyframes {
+            filepositions,
+            times,
+            fileposition_adjustment: 0f64,
+        }
+    }
+    /// Iterates `(fileposition, time)` pairs as found in the file, without
+    /// the adjustment.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, f64)> + '_ {
+        self.iter_patched(0f64)
+    }
+    /// Like `iter`, with `adjustment` added to every fileposition.
+    pub fn iter_patched(&self, adjustment: f64) -> impl Iterator<Item = (u64, f64)> + '_ {
+        self.filepositions
+            .iter()
+            .zip(&self.times)
+            .map(move |(&pos, &time)| ((pos + adjustment) as u64, time))
+    }
+    /// The last keyframe at or before `time`, as `(fileposition, time)`
+    /// without the adjustment like `iter`. `times` must be sorted.
+    pub fn find_nearest_before(&self, time: f64) -> Option<(u64, f64)> {
+        let index = self.times.partition_point(|&t| t <= time);
+        index.checked_sub(1).map(|index| self.get(index))
+    }
+    /// The first keyframe at or after `time`, see `find_nearest_before`.
+    pub fn find_nearest_after(&self, time: f64) -> Option<(u64, f64)> {
+        let index = self.times.partition_point(|&t| t < time);
+        if index == self.times.len() {
+            None
+        } else {
+            Some(self.get(index))
+        }
+    }
+    fn get(&self, index: usize) -> (u64, f64) {
+        (self.filepositions[index] as u64, self.times[index])
+    }
+    /// A WebVTT chapter track with one cue per keyframe, each lasting until
+    /// the next one. The last cue ends at `total_duration`, or is empty
+    /// without it.
+    pub fn to_webvtt(&self, total_duration: Option<f64>) -> String {
+        fn timestamp(seconds: f64) -> String {
+            let millis = (seconds.max(0f64) * 1000f64).round() as u64;
+            format!(
+                "{:02}:{:02}:{:02}.{:03}",
+                millis / 3_600_000,
+                millis / 60_000 % 60,
+                millis / 1000 % 60,
+                millis % 1000
+            )
+        }
+        let mut vtt = String::from("WEBVTT\n");
+        for (index, &start) in self.times.iter().enumerate() {
+            let end = match self.times.get(index + 1) {
+                Some(&next) => next,
+                None => total_duration.unwrap_or(start).max(start),
+            };
+            vtt += &format!(
+                "\n{}\n{} --> {}\nKeyframe {}\n",
+                index + 1,
+                timestamp(start),
+                timestamp(end),
+                index + 1
+            );
+        }
+        vtt
+    }
+    /// FFmpeg's metadata file with one chapter per keyframe, in
+    /// milliseconds. The last chapter ends at `total_duration_ms`.
+    pub fn to_ffmetadata(&self, total_duration_ms: u64) -> String {
+        let millis = |seconds: f64| (seconds.max(0f64) * 1000f64).round() as u64;
+        let mut metadata = String::from(";FFMETADATA1\n");
+        for (index, &start) in self.times.iter().enumerate() {
+            let start = millis(start);
+            let end = match self.times.get(index + 1) {
+                Some(&next) => millis(next),
+                None => total_duration_ms.max(start),
+            };
+            metadata += &format!(
+                "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle=Keyframe {}\n",
+                start,
+                end,
+                index + 1
+            );
+        }
+        metadata
+    }
+    /// `{"filepositions": [...], "times": [...]}` with the adjustment applied, for
+    /// players fetching the keyframes separately.
+    pub fn to_json(&self) -> serde_json::Value {
+        let filepositions: Vec<u64> = self
+            .iter_patched(self.fileposition_adjustment)
+            .map(|(pos, _)| pos)
+            .collect();
+        serde_json::json!({
+            "filepositions": filepositions,
+            "times": self.times,
+        })
+    }
+    /// The `keyframes` entry of `onMetaData`, with `fileposition_adjustment`
+    /// added to every fileposition.
     pub fn into_amf0(self) -> (String, amf::amf0::Value) {
         use amf::{
             amf0::{self, Value},
             Pair,
         };
-        let offset = self.offset;
+        let adjustment = self.fileposition_adjustment;
         let keyframes = Value::Object {
             class_name: None,
             entries: vec![
@@ -30,7 +278,7 @@ impl Keyframes {
                     value: amf0::array(
                         self.filepositions
                             .into_iter()
-                            .map(|i| i + offset)
+                            .map(|i| i + adjustment)
                             .map(amf0::number)
                             .collect(),
                     ),
@@ -44,3 +292,19 @@ impl Keyframes {
         ("keyframes".to_string(), keyframes)
     }
 }
+
+impl IntoIterator for Keyframes {
+    type Item = (u64, f64);
+    type IntoIter = std::iter::Map<
+        std::iter::Zip<std::vec::IntoIter<f64>, std::vec::IntoIter<f64>>,
+        fn((f64, f64)) -> (u64, f64),
+    >;
+
+    /// Same as `iter`, without the adjustment.
+    fn into_iter(self) -> Self::IntoIter {
+        self.filepositions
+            .into_iter()
+            .zip(self.times)
+            .map(|(pos, time)| (pos as u64, time))
+    }
+}
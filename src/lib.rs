@@ -0,0 +1,26 @@
+mod cancel;
+mod check;
+mod error;
+mod flv;
+mod flv_reader;
+mod keyframes;
+mod patch;
+
+pub use cancel::{CancellationToken, DropGuard};
+pub use check::{check_flv, CheckIssue, CheckReport};
+pub use error::{FlvError, Result};
+pub use flv::{
+    generate_patch, generate_patch_cancellable, generate_patch_from_reader,
+    generate_patch_with_options, generate_patch_with_progress, parse_metadata, read_keyframes,
+    read_patch_metadata, FlvMetadata, PatchOptions, PatchProgress, PatchResult, SkipReason,
+};
+pub use flv_codec;
+pub use flv_reader::{
+    frame_type_from_u8, frame_type_name, read_flv, read_flv_header, read_flv_tag,
+    read_flv_with_offsets, FlvHeader, FlvOffsetReader, FlvReader, FlvTag, FlvTagData, ScriptData,
+};
+pub use keyframes::Keyframes;
+pub use patch::{
+    reader_stream, reader_stream_with_chunk_size, CompressedPatch, Patch, PatchRegion,
+    PatchedReader, SyncPatchedReader,
+};
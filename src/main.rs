@@ -1,141 +1,1365 @@
-mod flv;
-mod keyframes;
-mod patch;
-
-use anyhow::Result;
-use flv::generate_patch;
-use headers::{HeaderMap, HeaderMapExt, Range};
-use patch::{reader_stream, Patch};
-use serde::Deserialize;
-use std::{io::SeekFrom, path::PathBuf, sync::Arc};
-use structopt::StructOpt;
+mod config;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod origin;
+mod patch_cache;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use clap::{builder::BoolishValueParser, CommandFactory, FromArgMatches, Parser, Subcommand};
+use flv_keyframes::{
+    check_flv, generate_patch_from_reader, generate_patch_with_options, parse_metadata,
+    read_flv_header, read_flv_tag, read_keyframes, read_patch_metadata,
+    reader_stream_with_chunk_size, FlvError, FlvMetadata, FlvTagData, Keyframes, Patch,
+    PatchOptions, PatchResult,
+};
+use futures::{
+    future,
+    stream::{self, StreamExt, TryStreamExt},
+};
+use headers::{
+    ETag, HeaderMap, HeaderMapExt, IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range,
+};
+#[cfg(feature = "metrics")]
+use metrics::Metrics;
+use origin::{Origin, RemoteFile};
+use patch_cache::PatchCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::SeekFrom,
+    net::IpAddr,
+    num::NonZeroUsize,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::{fs::File, prelude::*};
 use urlencoding::decode;
-use warp::{path::FullPath, Filter};
+use warp::{
+    filters::BoxedFilter,
+    http::{Method, StatusCode},
+    path::FullPath,
+    Filter, Reply,
+};
+
+/// Version with the commit it is built from, also sent as `Server` and
+/// `User-Agent`.
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("VERGEN_GIT_SHA"), ")");
+const SERVER: &str = concat!(
+    "flv-keyframes/",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("VERGEN_GIT_SHA"),
+    ")"
+);
 
-#[derive(StructOpt)]
+#[derive(Parser)]
+#[command(version = VERSION)]
 struct Args {
     /// root path to serve, default to "./"
-    #[structopt(short, long, parse(from_os_str))]
+    #[arg(short, long, env = "FLV_ROOT_PATH")]
     root_path: Option<PathBuf>,
-    #[structopt(long, default_value = "")]
+    /// directory below root_path the RelativePath of webhook calls is in
+    #[arg(long, default_value = "", env = "FLV_RELATIVE_PATH")]
     relative_path: String,
+    /// prefix of every URL but the webhook's like /media, for serving behind
+    /// a reverse proxy
+    #[arg(long, default_value = "", env = "FLV_BASE_PATH")]
+    base_path: String,
+    /// directory to store patch files in, default to next to the FLV files
+    #[arg(long, env = "FLV_PATCH_DIR")]
+    patch_dir: Option<PathBuf>,
+    /// ignore existing patch files and regenerate them on every request
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "FLV_FORCE_REGENERATE")]
+    force_regenerate: bool,
+    /// serve files as they are, for libraries that are already patched
+    #[arg(
+        long,
+        conflicts_with = "force_regenerate",
+        value_parser = BoolishValueParser::new(),
+        env = "FLV_NO_PATCH"
+    )]
+    no_patch: bool,
+    /// serve files larger than this many bytes without patching them
+    #[arg(long, env = "FLV_MAX_FILE_SIZE")]
+    max_file_size: Option<u64>,
+    /// serve files other than .flv as they are, like a static file server
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "FLV_PASS_THROUGH_NON_FLV")]
+    pass_through_non_flv: bool,
+    /// reply with a HTML page listing the files for directories
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "FLV_LIST_DIRECTORY")]
+    list_directory: bool,
+    /// only log invalid headers and pre tag sizes when generating patches
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "FLV_LENIENT")]
+    lenient: bool,
+    /// give up generating a patch after this many milliseconds, replying 503
+    #[arg(long, env = "FLV_TIMEOUT_MS")]
+    timeout_ms: Option<u64>,
+    /// store patch files compressed with zstd
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "FLV_COMPRESS_PATCHES")]
+    compress_patches: bool,
+    /// bytes read from disk per response body chunk
+    #[arg(
+        long,
+        default_value = "2048",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        env = "FLV_CHUNK_SIZE"
+    )]
+    chunk_size: usize,
+    /// patches kept in memory after being read, 0 reads them from disk on
+    /// every request
+    #[arg(long, default_value = "64", env = "FLV_PATCH_CACHE_ENTRIES")]
+    patch_cache_entries: usize,
+    /// port to listen on
+    #[arg(short, long, default_value = "3040", env = "FLV_PORT")]
+    port: u16,
+    /// origin allowed by CORS like https://example.com, can be repeated or
+    /// comma separated, any origin is allowed without it
+    #[arg(long, value_parser = parse_origin, env = "FLV_CORS_ORIGIN", value_delimiter = ',')]
+    cors_origin: Vec<String>,
+    /// header allowed by CORS besides range, can be repeated or comma separated
+    #[arg(long, env = "FLV_CORS_HEADER", value_delimiter = ',')]
+    cors_header: Vec<warp::http::header::HeaderName>,
+    /// address to bind, IPv4 or IPv6
+    #[arg(long, default_value = "0.0.0.0", env = "FLV_BIND")]
+    bind: IpAddr,
+    /// PEM certificate chain, serves HTTPS together with --tls-key
+    #[arg(long, requires = "tls_key", env = "FLV_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key, RSA or PKCS#8
+    #[arg(long, requires = "tls_cert", env = "FLV_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// one of trace, debug, info, warn or error
+    #[arg(long, default_value = "info", env = "FLV_LOG_LEVEL")]
+    log_level: tracing::Level,
+    /// path of the liveness probe, change it if a file is named the same
+    #[arg(
+        long,
+        default_value = "healthz",
+        value_parser = parse_health_path,
+        env = "FLV_HEALTH_PATH"
+    )]
+    health_path: String,
+    /// query parameter that makes `?<param>=1` reply as an attachment
+    #[arg(long, default_value = "download", env = "FLV_DOWNLOAD_PARAM")]
+    download_param: String,
+    /// serve files from this URL like https://cdn.example.com/flv instead of
+    /// root_path, the server has to support range requests
+    #[arg(long, env = "FLV_REMOTE_BASE_URL")]
+    remote_base_url: Option<String>,
+    /// generate patches of new and changed FLV files under root_path in the
+    /// background, instead of on the first request
+    #[arg(
+        long,
+        conflicts_with_all = ["no_patch", "remote_base_url"],
+        value_parser = BoolishValueParser::new(),
+        env = "FLV_WATCH"
+    )]
+    watch: bool,
+    /// generate patches on requests without storing them, replying with an
+    /// empty body, to see in the logs and metrics what a library needs
+    #[arg(
+        long,
+        conflicts_with = "watch",
+        value_parser = BoolishValueParser::new(),
+        env = "FLV_DRY_RUN"
+    )]
+    dry_run: bool,
+    /// serve Prometheus metrics at /metrics, needs the `metrics` feature
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "FLV_METRICS")]
+    metrics: bool,
+    /// read flags from this TOML file, flags on the command line win
+    #[arg(long, env = "FLV_CONFIG")]
+    config: Option<PathBuf>,
+    /// write a commented example of the --config file to this path and exit
+    #[arg(long, env = "FLV_CONFIG_EXAMPLE")]
+    config_example: Option<PathBuf>,
+    #[arg(skip)]
+    client: reqwest::Client,
+    /// set when `metrics` is
+    #[cfg(feature = "metrics")]
+    #[arg(skip)]
+    recorder: Option<Metrics>,
+    /// set unless `patch_cache_entries` is 0
+    #[arg(skip)]
+    patch_cache: Option<PatchCache>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// `root_path` is served when no subcommand is given
+#[derive(Subcommand)]
+enum Command {
+    /// print the header, metadata and keyframes of a FLV file
+    Inspect { file: PathBuf },
+    /// generate the patch of a FLV file, or of every FLV file in a directory
+    Patch {
+        /// walk the directory at `path` and patch every .flv file in it
+        #[arg(long)]
+        all: bool,
+        /// files to patch at the same time
+        #[arg(short, long, default_value = "4")]
+        jobs: usize,
+        path: PathBuf,
+    },
+    /// validate a FLV file, exits with 1 on warnings and 2 if it can't be read
+    Check { file: PathBuf },
+}
+
+fn print_metadata(metadata: &FlvMetadata) {
+    let fields = [
+        ("duration", metadata.duration),
+        ("width", metadata.width),
+        ("height", metadata.height),
+        ("framerate", metadata.framerate),
+        ("videocodecid", metadata.videocodecid),
+        ("audiocodecid", metadata.audiocodecid),
+    ];
+    for (name, value) in fields.iter() {
+        match value {
+            Some(value) => println!("  {:<14}{}", name, value),
+            None => println!("  {:<14}-", name),
+        }
+    }
+}
+
+fn inspect(path: &Path) -> Result<()> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut offset = 0;
+    let header = read_flv_header(&mut reader, &mut offset)?;
+    println!("header");
+    println!("  {:<14}{}", "has_audio", header.has_audio);
+    println!("  {:<14}{}", "has_video", header.has_video);
+    println!("  {:<14}{}", "data_offset", header.data_offset);
+
+    let mut metadata = None;
+    let mut keyframes = Keyframes::new();
+    while let Some(tag) = read_flv_tag(&mut reader, &mut offset)? {
+        match &tag.data {
+            FlvTagData::Script(_) if metadata.is_none() => {
+                // other script tags like onCuePoint are skipped
+                metadata = parse_metadata(&tag).ok();
+            }
+            FlvTagData::Video(_) if tag.is_keyframe() => {
+                keyframes.add(tag.byte_offset, tag.timestamp_as_duration().as_secs_f64());
+            }
+            _ => {}
+        }
+    }
+
+    match &metadata {
+        Some(metadata) => {
+            println!("metadata");
+            print_metadata(metadata);
+        }
+        None => println!("metadata      -"),
+    }
+    println!("keyframes     {}", keyframes.len());
+    if let Some(interval) = keyframes.average_interval() {
+        println!("  {:<14}{:.3}", "interval", interval);
+    }
+    if !keyframes.is_empty() {
+        println!("  {:>14}  {:>12}", "fileposition", "time");
+        for (position, time) in keyframes.iter() {
+            println!("  {:>14}  {:>12.3}", position, time);
+        }
+    }
+    Ok(())
+}
+
+/// Checks an origin the same way warp's CORS filter does, which panics instead.
+fn parse_origin(origin: &str) -> Result<String> {
+    let mut parts = origin.splitn(2, "://");
+    let scheme = parts.next().unwrap_or_default();
+    let host = parts
+        .next()
+        .ok_or_else(|| anyhow!("missing scheme in {}", origin))?;
+    headers::Origin::try_from_parts(scheme, host, None)
+        .map_err(|_| anyhow!("invalid origin {}", origin))?;
+    Ok(origin.to_string())
+}
+
+/// Checks the health path is a single segment, warp panics on empty ones and
+/// ones with a `/` inside. Slashes around it are allowed, like in `/healthz`.
+fn parse_health_path(path: &str) -> Result<String> {
+    let segment = path.trim_matches('/');
+    if segment.is_empty() || segment.contains('/') {
+        return Err(anyhow!(
+            "health path {:?} is not a single path segment like healthz",
+            path
+        ));
+    }
+    Ok(path.to_string())
 }
 
 fn map_not_found<T: std::fmt::Debug>(e: T) -> warp::Rejection {
-    println!("map_not_found {:?}", e);
+    tracing::warn!("map_not_found {:?}", e);
     warp::reject::not_found()
 }
 
+/// The requested range is outside of the patched file of `len` bytes.
+#[derive(Debug)]
+struct RangeNotSatisfiable {
+    len: u64,
+}
+
+impl std::fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "range not satisfiable, length is {}", self.len)
+    }
+}
+
+impl std::error::Error for RangeNotSatisfiable {}
+
+impl warp::reject::Reject for RangeNotSatisfiable {}
+
+/// Generating a patch took longer than `--timeout-ms`.
+#[derive(Debug)]
+struct PatchTimeout {
+    /// seconds for `Retry-After`
+    retry_after: u64,
+}
+
+impl std::fmt::Display for PatchTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "patch generation timed out")
+    }
+}
+
+impl std::error::Error for PatchTimeout {}
+
+impl warp::reject::Reject for PatchTimeout {}
+
+fn map_reply_error(e: anyhow::Error) -> warp::Rejection {
+    let e = match e.downcast::<RangeNotSatisfiable>() {
+        Ok(e) => return warp::reject::custom(e),
+        Err(e) => e,
+    };
+    match e.downcast::<PatchTimeout>() {
+        Ok(e) => warp::reject::custom(e),
+        Err(e) => map_not_found(e),
+    }
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(RangeNotSatisfiable { len }) = err.find() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(warp::reply(), StatusCode::RANGE_NOT_SATISFIABLE),
+            "Content-Range",
+            format!("bytes */{}", len),
+        )
+        .into_response());
+    }
+    if let Some(PatchTimeout { retry_after }) = err.find() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(warp::reply(), StatusCode::SERVICE_UNAVAILABLE),
+            "Retry-After",
+            retry_after.to_string(),
+        )
+        .into_response());
+    }
+    Err(err)
+}
+
+/// A random 8 digit hex id, recorded in the request span so every log
+/// line of the request carries it.
+fn new_request_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+
+    // every `RandomState` is seeded differently
+    let id = format!(
+        "{:08x}",
+        std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish() as u32
+    );
+    tracing::Span::current().record("id", &tracing::field::display(&id));
+    id
+}
+
 fn with_args(
     args: Arc<Args>,
 ) -> impl Filter<Extract = (Arc<Args>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || args.clone())
 }
 
-async fn generate_keyframes(path: PathBuf, patch_path: PathBuf) -> Result<Option<File>> {
-    let file = File::open(path.clone()).await?;
-    let patch = generate_patch(file).await?;
-    if let Some(patch) = patch {
-        let patch = bincode::serialize(&patch)?;
-        let mut patch_file = File::create(patch_path.clone()).await?;
-        patch_file.write_all(&patch).await?;
-        return Ok(Some(File::open(patch_path).await?));
+#[cfg(not(feature = "json-patch"))]
+const PATCH_EXTENSION: &str = "v1.binpatch";
+#[cfg(not(feature = "json-patch"))]
+const COMPRESSED_PATCH_EXTENSION: &str = "v1.zst.binpatch";
+#[cfg(feature = "json-patch")]
+const PATCH_EXTENSION: &str = "v0.jsonpatch";
+#[cfg(feature = "json-patch")]
+const COMPRESSED_PATCH_EXTENSION: &str = "v0.zst.jsonpatch";
+
+/// Where the patch of the file at `relative` to the root path is stored.
+fn patch_path(args: &Args, relative: &Path) -> PathBuf {
+    let mut patch_path = match &args.patch_dir {
+        Some(patch_dir) => patch_dir.join(relative),
+        None => args.root_path.clone().unwrap_or_default().join(relative),
+    };
+    let filename = patch_path.file_name().unwrap_or_default().to_os_string();
+    patch_path.set_file_name(format!(".{}", filename.to_string_lossy()));
+    patch_path.set_extension(if args.compress_patches {
+        COMPRESSED_PATCH_EXTENSION
+    } else {
+        PATCH_EXTENSION
+    });
+    patch_path
+}
+
+/// Opens the patch of `path`, unless it is missing or older than the source file.
+async fn open_fresh_patch(path: &Path, patch_path: &Path) -> Option<File> {
+    let source = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+    let patch = tokio::fs::metadata(patch_path)
+        .await
+        .ok()?
+        .modified()
+        .ok()?;
+    if patch < source {
+        return None;
     }
-    return Ok(None);
+    File::open(patch_path).await.ok()
 }
 
-async fn reply_with_patch(
+async fn generate_keyframes(
+    args: &Args,
     path: PathBuf,
+    patch_path: PathBuf,
+) -> Result<Option<File>> {
+    let file = File::open(path.clone()).await?;
+    let options = PatchOptions {
+        lenient: args.lenient,
+        ..PatchOptions::default()
+    };
+    // stop scanning when the request is dropped before the patch is done
+    let _guard = options.cancel.clone().drop_guard();
+    let result =
+        match with_patch_timeout(args, &path, generate_patch_with_options(file, options)).await {
+            Err(e) if matches!(e.downcast_ref(), Some(FlvError::FileModifiedDuringPatch)) => {
+                tracing::warn!(
+                    "{} changed while generating its patch, serving it unpatched",
+                    path.display()
+                );
+                return Ok(None);
+            }
+            result => result?,
+        };
+    store_patch(args, &path, result, patch_path).await
+}
+
+/// Awaits `generate`, giving up after `--timeout-ms`. The scan itself stops
+/// once the caller drops the cancellation guard of its options.
+async fn with_patch_timeout(
+    args: &Args,
+    path: &Path,
+    generate: impl Future<Output = flv_keyframes::Result<PatchResult>>,
+) -> Result<PatchResult> {
+    #[cfg(feature = "metrics")]
+    let start = Instant::now();
+    let observe = |result: PatchResult| {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &args.recorder {
+            metrics
+                .patch_generation
+                .observe(start.elapsed().as_secs_f64());
+        }
+        result
+    };
+    let timeout_ms = match args.timeout_ms {
+        Some(timeout_ms) => timeout_ms,
+        None => return Ok(observe(generate.await?)),
+    };
+    match tokio::time::timeout(Duration::from_millis(timeout_ms), generate).await {
+        Ok(result) => Ok(observe(result?)),
+        Err(_) => {
+            tracing::warn!(
+                "generating the patch of {} timed out after {}ms",
+                path.display(),
+                timeout_ms
+            );
+            Err(PatchTimeout {
+                retry_after: timeout_ms.div_ceil(1000),
+            }
+            .into())
+        }
+    }
+}
+
+/// Logs what the scan of `path` found and writes its patch, if any.
+async fn store_patch(
+    args: &Args,
+    path: &Path,
+    result: PatchResult,
+    patch_path: PathBuf,
+) -> Result<Option<File>> {
+    tracing::debug!(
+        keyframes = result.keyframe_count,
+        tags = result.total_tags,
+        duration = result.duration_secs,
+        skipped = ?result.reason_skipped,
+        "scanned {}",
+        path.display()
+    );
+    match result.patch {
+        Some(_) if args.dry_run => {
+            tracing::info!("{} needs a patch, not storing it", path.display());
+            Ok(None)
+        }
+        Some(patch) => Ok(Some(write_patch(args, patch, patch_path).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Stores `patch` at `patch_path` and opens it again for reading.
+async fn write_patch(args: &Args, patch: Patch, patch_path: PathBuf) -> Result<File> {
+    if args.compress_patches {
+        patch.compress()?.save(&patch_path).await?;
+    } else {
+        patch.save(&patch_path).await?;
+    }
+    Ok(File::open(patch_path).await?)
+}
+
+async fn read_patch(patch_file: Option<File>) -> Result<Patch> {
+    Ok(match patch_file {
+        Some(patch_file) => Patch::read_from(patch_file).await?,
+        None => Patch::default(),
+    })
+}
+
+/// `read_patch` through `--patch-cache-entries`, `patch_path` is where
+/// `patch_file` was opened.
+async fn read_cached_patch(
+    args: &Args,
+    patch_path: PathBuf,
     patch_file: Option<File>,
-    range: Option<Range>,
-) -> Result<warp::hyper::Response<warp::hyper::Body>> {
-    use std::ops::Bound;
+) -> Result<Arc<Patch>> {
+    let (cache, patch_file) = match (&args.patch_cache, patch_file) {
+        (Some(cache), Some(patch_file)) => (cache, patch_file),
+        (_, patch_file) => return Ok(Arc::new(read_patch(patch_file).await?)),
+    };
+    let metadata = patch_file.metadata().await?;
+    let (len, modified) = (metadata.len(), metadata.modified()?);
+    if let Some(patch) = cache.get(&patch_path, len, modified) {
+        return Ok(patch);
+    }
+    let patch = Arc::new(read_patch(Some(patch_file)).await?);
+    cache.put(patch_path, len, modified, patch.clone());
+    Ok(patch)
+}
 
-    let patch: Patch = match patch_file {
-        Some(mut patch_file) => {
-            let mut buf = vec![];
-            patch_file.read_to_end(&mut buf).await?;
-            bincode::deserialize(&buf[..])?
+/// Opens the patch of `path`, generating it first if needed. `None` means
+/// the file is served as is.
+async fn ensure_patch(args: &Args, path: &Path, relative: &Path) -> Result<Option<File>> {
+    if args.no_patch {
+        return Ok(None);
+    }
+    let patch_path = patch_path(args, relative);
+    let patch = if args.force_regenerate {
+        None
+    } else {
+        open_fresh_patch(path, &patch_path).await
+    };
+    count_cache_lookup(args, patch.is_some());
+    match patch {
+        Some(pf) => Ok(Some(pf)),
+        None => generate_keyframes(args, path.to_path_buf(), patch_path).await,
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn count_cache_lookup(args: &Args, hit: bool) {
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &args.recorder {
+        if hit {
+            metrics.cache_hits.inc();
+        } else {
+            metrics.cache_misses.inc();
         }
-        None => Patch {
-            origin_pos: 0,
-            origin_size: 0,
-            patched: vec![],
-        },
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn count_request(args: &Args, status: StatusCode) {
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &args.recorder {
+        metrics.requests.with_label_values(&[status.as_str()]).inc();
+    }
+}
+
+/// Where the `RemoteFile::version` a remote patch was generated from is kept.
+fn remote_version_path(patch_path: &Path) -> PathBuf {
+    let mut path = patch_path.as_os_str().to_os_string();
+    path.push(".remote");
+    PathBuf::from(path)
+}
+
+/// Like `ensure_patch` for a remote file. Instead of the modification time,
+/// the stored patch is used while the remote length and `ETag` are the ones
+/// it was generated from.
+async fn ensure_remote_patch(
+    args: &Args,
+    remote: &RemoteFile,
+    relative: &Path,
+) -> Result<Option<File>> {
+    if args.no_patch {
+        return Ok(None);
+    }
+    let patch_path = patch_path(args, relative);
+    let version_path = remote_version_path(&patch_path);
+    let version = remote.version();
+    if !args.force_regenerate {
+        let stored = tokio::fs::read_to_string(&version_path).await.ok();
+        if stored.as_deref() == Some(version.as_str()) {
+            if let Ok(patch_file) = File::open(&patch_path).await {
+                count_cache_lookup(args, true);
+                return Ok(Some(patch_file));
+            }
+        }
+    }
+    count_cache_lookup(args, false);
+    // the whole file is read once
+    let response = remote.download().await?;
+    let options = PatchOptions {
+        lenient: args.lenient,
+        ..PatchOptions::default()
     };
+    let _guard = options.cancel.clone().drop_guard();
+    let result = with_patch_timeout(
+        args,
+        relative,
+        generate_patch_from_reader(response, remote.len(), options),
+    )
+    .await?;
+    let patch_file = store_patch(args, relative, result, patch_path).await?;
+    if patch_file.is_some() {
+        tokio::fs::write(&version_path, version).await?;
+    }
+    Ok(patch_file)
+}
+
+/// The patch of a file of `len` bytes as it is served, `None` for files
+/// served as they are: non-FLV files with `--pass-through-non-flv`, files
+/// above `--max-file-size` and every file with `--no-patch`.
+async fn served_patch_file(
+    args: &Args,
+    origin: &Origin,
+    len: u64,
+    relative: &Path,
+) -> Result<Option<File>> {
+    match args.max_file_size {
+        _ if args.pass_through_non_flv && !is_flv(relative) => Ok(None),
+        Some(max) if len > max => {
+            tracing::warn!(
+                "{} is larger than {} bytes, serving it unpatched",
+                origin.location(),
+                max
+            );
+            Ok(None)
+        }
+        _ => match origin {
+            Origin::Local(path) => ensure_patch(args, path, relative).await,
+            Origin::Remote(remote) => ensure_remote_patch(args, remote, relative).await,
+        },
+    }
+}
+
+/// The URL of `raw`, a still URL encoded request path, below `base_url`.
+fn remote_url(base_url: &str, raw: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), raw)
+}
 
-    let file = File::open(path).await?;
-    let mut reader = patch.patch_reader(file).await?;
+/// `Content-Disposition` of a download, only the file name is kept and
+/// anything that could break out of the quotes is replaced.
+fn set_attachment(reply: &mut warp::hyper::Response<warp::hyper::Body>, path: &Path) {
+    let value = content_disposition(path)
+        .parse()
+        .expect("printable ASCII is a valid header");
+    reply.headers_mut().insert("Content-Disposition", value);
+}
+
+fn content_disposition(path: &Path) -> String {
+    let name: String = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if !matches!(c, '"' | '\\' | '%') => c,
+            _ => '_',
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"", name)
+}
+
+/// The part of a request path after `base`, without the leading slash.
+fn strip_base_path<'a>(base: &str, path: &'a str) -> Option<&'a str> {
+    let base = base.trim_matches('/');
+    let path = path.strip_prefix('/')?;
+    if base.is_empty() {
+        return Some(path);
+    }
+    path.strip_prefix(base)?.strip_prefix('/')
+}
+
+/// Whether `relative` stays below the directory it is joined onto, that is
+/// it has no `..`, root or prefix.
+fn is_below_root(relative: &Path) -> bool {
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// The percent-decoded path of a request below `--root-path`.
+fn decode_relative(raw: &str) -> Result<PathBuf, warp::Rejection> {
+    let relative = PathBuf::from(decode(raw).map_err(map_not_found)?);
+    if !is_below_root(&relative) {
+        return Err(warp::reject::not_found());
+    }
+    Ok(relative)
+}
+
+fn mime_for_path(path: &Path) -> &'static str {
+    if is_flv(path) {
+        return "video/x-flv";
+    }
+    mime_guess::from_path(path)
+        .first_raw()
+        .unwrap_or("application/octet-stream")
+}
+
+/// Parts of a `multipart/byteranges` body are separated by this, it is
+/// unlikely to show up in a video.
+const MULTIPART_BOUNDARY: &str = "3d6b6a416f9b5f7c9e2f1d0c";
+
+async fn reply_with_patch(
+    origin: Origin,
+    patch: Arc<Patch>,
+    range: Option<Range>,
+    with_body: bool,
+    chunk_size: usize,
+) -> Result<warp::hyper::Response<warp::hyper::Body>> {
+    use std::ops::Bound;
+
+    let mut reader = patch.patch_reader(origin.open().await?).await?;
     let max_len = reader.len();
-    let range = if let Some(range) = range {
-        range
+    // unsatisfiable ranges are left out, unless that leaves nothing
+    let ranges: Vec<(u64, u64)> = match &range {
+        Some(range) => range
             .iter()
-            .map(|(start, end)| {
+            .filter_map(|(start, end)| {
+                // `bytes=-n` comes without a start, it is the last n bytes
+                if let (Bound::Unbounded, Bound::Included(n)) = (start, end) {
+                    return Some((max_len.saturating_sub(n), max_len))
+                        .filter(|(start, end)| start < end);
+                }
                 let start = match start {
                     Bound::Unbounded => 0,
                     Bound::Included(s) => s,
                     Bound::Excluded(s) => s + 1,
                 };
+                // exclusive, a range past the end is cut to the end
                 let end = match end {
                     Bound::Unbounded => max_len,
-                    Bound::Included(s) => s,
-                    Bound::Excluded(s) => s + 1,
-                };
-                if start < end && end <= max_len {
-                    io::Result::Ok((start, end))
+                    Bound::Included(s) => s + 1,
+                    Bound::Excluded(s) => s,
+                }
+                .min(max_len);
+                if start < end {
+                    Some((start, end))
                 } else {
-                    Err(io::ErrorKind::InvalidData.into())
+                    None
                 }
             })
-            .next()
-            .unwrap_or(Ok((0, max_len)))
-    } else {
-        io::Result::Ok((0, max_len))
-    }?;
-    reader.seek(SeekFrom::Start(range.0)).await?;
-    let reader = reader.take(range.1 - range.0);
-    let stream = reader_stream(reader);
+            .collect(),
+        None => vec![],
+    };
+    if range.is_some() && ranges.is_empty() {
+        return Err(RangeNotSatisfiable { len: max_len }.into());
+    }
     let mut builder = warp::http::Response::builder();
-    builder = builder.header("Content-Length", range.1 - range.0);
-    builder = builder.header(
-        "Content-Range",
-        format!("bytes {}-{}/{}", range.0, range.1, max_len),
-    );
+    builder = builder.header("Accept-Ranges", "bytes");
+    if ranges.len() > 1 {
+        return reply_multipart(
+            builder, origin, patch, ranges, max_len, with_body, chunk_size,
+        );
+    }
 
+    // the whole patched file without a range
+    let range = ranges.first().copied().unwrap_or((0, max_len));
+    let content_length = range.1 - range.0;
+    builder = builder.header("Content-Type", mime_for_path(origin.path()));
+    builder = builder.header("Content-Length", content_length);
+    if !ranges.is_empty() {
+        builder = builder.status(StatusCode::PARTIAL_CONTENT);
+        builder = builder.header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", range.0, range.1 - 1, max_len),
+        );
+    }
+
+    if !with_body {
+        return Ok(builder.body(warp::hyper::Body::empty())?);
+    }
+    reader.seek(SeekFrom::Start(range.0)).await?;
+    let reader = reader.take(content_length);
+    let stream = reader_stream_with_chunk_size(reader, chunk_size);
     Ok(builder.body(warp::hyper::Body::wrap_stream(stream))?)
 }
 
+/// Replies with a `multipart/byteranges` body, every part is read through
+/// its own patched reader.
+fn reply_multipart(
+    builder: warp::http::response::Builder,
+    origin: Origin,
+    patch: Arc<Patch>,
+    ranges: Vec<(u64, u64)>,
+    max_len: u64,
+    with_body: bool,
+    chunk_size: usize,
+) -> Result<warp::hyper::Response<warp::hyper::Body>> {
+    let parts: Vec<_> = ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let head = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                MULTIPART_BOUNDARY,
+                mime_for_path(origin.path()),
+                start,
+                end - 1,
+                max_len
+            );
+            (Bytes::from(head), start, end)
+        })
+        .collect();
+    let tail = Bytes::from(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY));
+    let content_length = parts
+        .iter()
+        .map(|(head, start, end)| head.len() as u64 + end - start)
+        .sum::<u64>()
+        + tail.len() as u64;
+    let builder = builder
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", MULTIPART_BOUNDARY),
+        )
+        .header("Content-Length", content_length);
+
+    if !with_body {
+        return Ok(builder.body(warp::hyper::Body::empty())?);
+    }
+    let stream = stream::iter(parts)
+        .then(move |(head, start, end)| {
+            let origin = origin.clone();
+            let patch = patch.clone();
+            async move {
+                let mut reader = patch.patch_reader(origin.open().await?).await?;
+                reader.seek(SeekFrom::Start(start)).await?;
+                let body = reader_stream_with_chunk_size(reader.take(end - start), chunk_size);
+                Ok::<_, std::io::Error>(stream::once(future::ready(Ok(head))).chain(body))
+            }
+        })
+        .try_flatten()
+        .chain(stream::once(future::ready(Ok(tail))));
+    Ok(builder.body(warp::hyper::Body::wrap_stream(stream))?)
+}
+
+/// Validator of the source FLV, which also covers the patch derived from it.
+fn file_etag(modified: SystemTime, len: u64) -> ETag {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("\"{:x}-{:x}\"", modified, len)
+        .parse()
+        .expect("hex digits are a valid etag")
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &ETag, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+        return !if_none_match.precondition_passes(etag);
+    }
+    match headers.typed_get::<IfModifiedSince>() {
+        Some(since) => !since.is_modified(modified),
+        None => false,
+    }
+}
+
 async fn handle_get(
+    method: Method,
     args: Arc<Args>,
     path: FullPath,
+    query: HashMap<String, String>,
     headers: HeaderMap,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let range: Option<Range> = headers.typed_get();
+    let download = query.get(&args.download_param).map(String::as_str) == Some("1");
     let root_path = args.root_path.clone().unwrap_or_default();
-    let p = decode(&path.as_str()[1..]).map_err(map_not_found)?;
-    let path = root_path.join(PathBuf::from(p));
-    let mut patch_path = path.clone();
-    let filename = patch_path.file_name().unwrap_or_default().to_os_string();
-    patch_path.set_file_name(format!(".{}", filename.to_string_lossy()));
-    patch_path.set_extension("v0.binpatch");
-    let patch = File::open(&patch_path).await;
-
-    let patch_file = match patch {
-        Ok(pf) => Some(pf),
-        Err(_) => generate_keyframes(path.clone(), patch_path)
+    let raw =
+        strip_base_path(&args.base_path, path.as_str()).ok_or_else(warp::reject::not_found)?;
+    let relative = decode_relative(raw)?;
+    let with_body = method != Method::HEAD;
+    if let Some(base_url) = &args.remote_base_url {
+        let url = remote_url(base_url, raw);
+        let mut reply = remote_get(&args, url, &relative, range, with_body).await?;
+        if download {
+            set_attachment(&mut reply, &relative);
+        }
+        return Ok(reply);
+    }
+    let url_path = path.as_str().trim_end_matches('/').to_string();
+    let path = root_path.join(&relative);
+    let metadata = tokio::fs::metadata(&path).await.map_err(map_not_found)?;
+    if metadata.is_dir() {
+        if !args.list_directory {
+            return Err(warp::reject::not_found());
+        }
+        let html = list_directory(&args, &path, &relative, &url_path)
             .await
-            .map_err(map_not_found)?,
+            .map_err(map_not_found)?;
+        let reply = warp::http::Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(warp::hyper::Body::from(html))
+            .map_err(map_not_found)?;
+        return Ok(reply);
+    }
+    let modified = metadata.modified().map_err(map_not_found)?;
+    let etag = file_etag(modified, metadata.len());
+    // a resumed download of an older version gets the whole file again
+    let range = match headers.typed_get::<IfRange>() {
+        Some(if_range) if if_range.is_modified(Some(&etag), Some(&modified.into())) => None,
+        _ => range,
     };
+    if is_not_modified(&headers, &etag, modified) {
+        let mut reply = warp::http::Response::new(warp::hyper::Body::empty());
+        *reply.status_mut() = StatusCode::NOT_MODIFIED;
+        reply.headers_mut().typed_insert(etag);
+        return Ok(reply);
+    }
+    let origin = Origin::Local(path);
+    let patch_file = served_patch_file(&args, &origin, metadata.len(), &relative)
+        .await
+        .map_err(map_reply_error)?;
+    if args.dry_run {
+        return Ok(dry_run_reply());
+    }
+    let patch = read_cached_patch(&args, patch_path(&args, &relative), patch_file)
+        .await
+        .map_err(map_reply_error)?;
 
-    let reply = reply_with_patch(path, patch_file, range)
+    let mut reply = reply_with_patch(origin, patch, range, with_body, args.chunk_size)
         .await
-        .map_err(map_not_found)?;
+        .map_err(map_reply_error)?;
+    if download {
+        set_attachment(&mut reply, &relative);
+    }
+    reply.headers_mut().typed_insert(etag);
+    reply
+        .headers_mut()
+        .typed_insert(LastModified::from(modified));
     Ok(reply)
 }
 
+/// Serves a file below `--remote-base-url`, without validators since the
+/// remote file is never looked at besides its length.
+async fn remote_get(
+    args: &Args,
+    url: String,
+    relative: &Path,
+    range: Option<Range>,
+    with_body: bool,
+) -> Result<warp::hyper::Response<warp::hyper::Body>, warp::Rejection> {
+    let remote = RemoteFile::head(args.client.clone(), url, relative.to_path_buf())
+        .await
+        .map_err(map_not_found)?;
+    let len = remote.len();
+    let origin = Origin::Remote(remote);
+    let patch_file = served_patch_file(args, &origin, len, relative)
+        .await
+        .map_err(map_reply_error)?;
+    if args.dry_run {
+        return Ok(dry_run_reply());
+    }
+    let patch = read_cached_patch(args, patch_path(args, relative), patch_file)
+        .await
+        .map_err(map_reply_error)?;
+    reply_with_patch(origin, patch, range, with_body, args.chunk_size)
+        .await
+        .map_err(map_reply_error)
+}
+
+/// What `--dry-run` replies once the patch is looked up or generated.
+fn dry_run_reply() -> warp::hyper::Response<warp::hyper::Body> {
+    warp::hyper::Response::new(warp::hyper::Body::empty())
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A HTML page linking every entry of `dir`, which is served at `url_path`.
+/// FLV files with a patch also show their patched size and keyframe count.
+async fn list_directory(
+    args: &Args,
+    dir: &Path,
+    relative: &Path,
+    url_path: &str,
+) -> Result<String> {
+    let mut entries = vec![];
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // patches are stored as hidden files
+        if name.starts_with('.') {
+            continue;
+        }
+        entries.push((entry.file_type().await?.is_dir(), name));
+    }
+    // directories first
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let title = escape_html(&format!("/{}", relative.display()));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<ul>\n",
+        title
+    );
+    if let Some((parent, _)) = url_path.rsplit_once('/') {
+        html += &format!("<li><a href=\"{}/\">../</a></li>\n", escape_html(parent));
+    }
+    for (is_dir, name) in entries {
+        let href = format!("{}/{}", url_path, urlencoding::encode(&name));
+        if is_dir {
+            html += &format!(
+                "<li><a href=\"{}/\">{}/</a></li>\n",
+                escape_html(&href),
+                escape_html(&name)
+            );
+            continue;
+        }
+        let path = dir.join(&name);
+        let mut details = String::new();
+        if is_flv(&path) {
+            let patch_path = patch_path(args, &relative.join(&name));
+            if let Some(patch_file) = open_fresh_patch(&path, &patch_path).await {
+                // a broken patch only leaves its entry without details
+                match patch_details(args, &path, patch_path, patch_file).await {
+                    Ok(patched) => details = patched,
+                    Err(e) => {
+                        tracing::warn!("failed to read the patch of {}: {:?}", path.display(), e)
+                    }
+                }
+            }
+        }
+        html += &format!(
+            "<li><a href=\"{}\">{}</a>{}</li>\n",
+            escape_html(&href),
+            escape_html(&name),
+            details
+        );
+    }
+    html += "</ul>\n</body>\n</html>\n";
+    Ok(html)
+}
+
+/// The patched size and keyframe count of `path` shown in directory listings.
+async fn patch_details(
+    args: &Args,
+    path: &Path,
+    patch_path: PathBuf,
+    patch_file: File,
+) -> Result<String> {
+    let patch = read_cached_patch(args, patch_path, Some(patch_file)).await?;
+    let len = tokio::fs::metadata(path).await?.len();
+    let keyframes = match read_patch_metadata(&patch)? {
+        Some((keyframes, _)) => keyframes.len().to_string(),
+        None => "-".to_string(),
+    };
+    Ok(format!(
+        " ({} bytes patched, {} keyframes)",
+        (len as i64 + patch.size_delta()) as u64,
+        keyframes
+    ))
+}
+
+/// Prints the issues of the file at `path`, returning the exit code.
+fn check(path: &Path) -> Result<i32> {
+    let report = check_flv(std::fs::File::open(path)?);
+    println!("tags          {}", report.tag_count);
+    for issue in &report.issues {
+        let level = if issue.is_fatal() { "error" } else { "warning" };
+        println!("{}: {}", level, issue);
+    }
+    Ok(if report.has_fatal() {
+        2
+    } else if !report.issues.is_empty() {
+        1
+    } else {
+        0
+    })
+}
+
+fn is_flv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("flv"))
+}
+
+/// Collects every `.flv` file under `dir`, recursively.
+fn find_flv_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_flv_files(&path, files)?;
+        } else if is_flv(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Regenerates patches ahead of time, the same way the first request would.
+async fn patch(args: &Args, path: PathBuf, all: bool, jobs: usize) -> Result<()> {
+    let files = if all {
+        let mut files = vec![];
+        find_flv_files(&path, &mut files)?;
+        files
+    } else {
+        vec![path]
+    };
+    let root_path = args.root_path.clone().unwrap_or_default();
+    let results: Vec<_> = stream::iter(files)
+        .map(|file| {
+            let relative = file.strip_prefix(&root_path).unwrap_or(&file);
+            let patch_path = patch_path(args, relative);
+            async move {
+                let result = generate_keyframes(args, file.clone(), patch_path).await;
+                (file, result)
+            }
+        })
+        .buffer_unordered(jobs.max(1))
+        .collect()
+        .await;
+    let mut failed = 0;
+    for (file, result) in results {
+        match result {
+            Ok(Some(_)) => println!("patched {}", file.display()),
+            Ok(None) => println!("skipped {}, nothing to patch", file.display()),
+            Err(e) => {
+                eprintln!("failed {}: {:?}", file.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} file(s) failed", failed));
+    }
+    Ok(())
+}
+
+/// How long a file has to stay unchanged before it is patched, recordings
+/// are written to all the time.
+const WATCH_QUIET: Duration = Duration::from_secs(2);
+
+/// Patches FLV files under the root path as they are created or modified,
+/// until the watcher fails.
+async fn watch(args: Arc<Args>) -> Result<()> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    let root_path = args.root_path.clone().unwrap_or_else(|| PathBuf::from("."));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("watch error {:?}", e),
+        })?;
+    watcher.watch(&root_path, RecursiveMode::Recursive)?;
+    tracing::info!("watching {}", root_path.display());
+    // events come with absolute paths
+    let watched = root_path.canonicalize()?;
+
+    // last change of every file waiting to be patched
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        match tokio::time::timeout(Duration::from_secs(1), rx.recv()).await {
+            Ok(Some(path)) if is_flv(&path) => {
+                pending.insert(path, Instant::now());
+            }
+            Ok(Some(_)) | Err(_) => {}
+            Ok(None) => return Err(anyhow!("watcher stopped")),
+        }
+        let quiet: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= WATCH_QUIET)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in quiet {
+            pending.remove(&path);
+            let relative = path.strip_prefix(&watched).unwrap_or(&path);
+            let patch_path = patch_path(&args, relative);
+            if open_fresh_patch(&path, &patch_path).await.is_some() {
+                continue;
+            }
+            match generate_keyframes(&args, path.clone(), patch_path).await {
+                Ok(Some(_)) => tracing::info!("patched {}", path.display()),
+                Ok(None) => tracing::info!("skipped {}, nothing to patch", path.display()),
+                Err(e) => tracing::error!("failed to patch {}: {:?}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Serves `/chapters/<file>.vtt`, a WebVTT chapter per keyframe of `<file>`.
+async fn handle_chapters(
+    args: Arc<Args>,
+    tail: warp::path::Tail,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let relative = decode_relative(tail.as_str())?;
+    let relative = match relative.to_str().and_then(|p| p.strip_suffix(".vtt")) {
+        Some(relative) => PathBuf::from(relative),
+        None => return Err(warp::reject::not_found()),
+    };
+    let path = args.root_path.clone().unwrap_or_default().join(relative);
+    let file = std::fs::File::open(path).map_err(map_not_found)?;
+    let (keyframes, metadata) = tokio::task::spawn_blocking(move || read_keyframes(file))
+        .await
+        .map_err(map_not_found)?
+        .map_err(map_not_found)?;
+    Ok(warp::reply::with_header(
+        keyframes.to_webvtt(metadata.duration),
+        "Content-Type",
+        "text/vtt; charset=utf-8",
+    ))
+}
+
+#[derive(Serialize)]
+struct KeyframeJson {
+    pos: u64,
+    time: f64,
+}
+
+/// Response of `/api/info/<file>`, positions are those of the served file.
+#[derive(Serialize)]
+struct KeyframesJson {
+    duration: Option<f64>,
+    width: Option<f64>,
+    height: Option<f64>,
+    keyframes: Vec<KeyframeJson>,
+}
+
+/// Keyframes and metadata of the file at `relative` as it is served, the
+/// positions are those of the patched file. `raw` is the still URL encoded
+/// request path.
+async fn served_keyframes(
+    args: &Args,
+    raw: &str,
+    relative: &Path,
+) -> Result<(Keyframes, FlvMetadata)> {
+    let (origin, len) = match &args.remote_base_url {
+        Some(base_url) => {
+            let url = remote_url(base_url, raw);
+            let remote = RemoteFile::head(args.client.clone(), url, relative.to_path_buf()).await?;
+            let len = remote.len();
+            (Origin::Remote(remote), len)
+        }
+        None => {
+            let path = args.root_path.clone().unwrap_or_default().join(relative);
+            let len = tokio::fs::metadata(&path).await?.len();
+            (Origin::Local(path), len)
+        }
+    };
+    let patch_file = served_patch_file(args, &origin, len, relative).await?;
+    let patch = read_cached_patch(args, patch_path(args, relative), patch_file).await?;
+    if let Some(info) = read_patch_metadata(&patch)? {
+        return Ok(info);
+    }
+    // not patched, the file already has everything, unless it is one too
+    // large to be looked at
+    if let Some(max) = args.max_file_size.filter(|&max| len > max) {
+        return Err(anyhow!(
+            "{} is larger than {} bytes",
+            origin.location(),
+            max
+        ));
+    }
+    Ok(match origin {
+        Origin::Local(path) => {
+            let file = std::fs::File::open(path)?;
+            tokio::task::spawn_blocking(move || read_keyframes(file)).await??
+        }
+        Origin::Remote(remote) => {
+            let body = remote.download().await?;
+            tokio::task::spawn_blocking(move || read_keyframes(body)).await??
+        }
+    })
+}
+
+async fn info(args: &Args, raw: &str, relative: &Path) -> Result<KeyframesJson> {
+    let (keyframes, metadata) = served_keyframes(args, raw, relative).await?;
+    Ok(KeyframesJson {
+        duration: metadata.duration,
+        width: metadata.width,
+        height: metadata.height,
+        keyframes: keyframes
+            .iter()
+            .map(|(pos, time)| KeyframeJson { pos, time })
+            .collect(),
+    })
+}
+
+async fn handle_info(
+    args: Arc<Args>,
+    tail: warp::path::Tail,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let relative = decode_relative(tail.as_str())?;
+    let info = info(&args, tail.as_str(), &relative)
+        .await
+        .map_err(map_not_found)?;
+    Ok(warp::reply::json(&info))
+}
+
+/// Serves `/api/keyframes/<file>`, the keyframes of `Keyframes::to_json`.
+async fn handle_keyframes(
+    args: Arc<Args>,
+    tail: warp::path::Tail,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let relative = decode_relative(tail.as_str())?;
+    let (keyframes, _) = served_keyframes(&args, tail.as_str(), &relative)
+        .await
+        .map_err(map_not_found)?;
+    Ok(warp::reply::json(&keyframes.to_json()))
+}
+
+/// Serves `/metrics` in the Prometheus text format, only with `--metrics`.
+#[cfg(feature = "metrics")]
+async fn handle_metrics(args: Arc<Args>) -> Result<impl warp::Reply, warp::Rejection> {
+    let metrics = args.recorder.as_ref().ok_or_else(warp::reject::not_found)?;
+    let text = metrics.render().map_err(map_not_found)?;
+    Ok(warp::reply::with_header(
+        text,
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
+#[allow(dead_code)]
 struct HookArgs {
     event_random_id: String,
     room_id: u64,
@@ -151,51 +1375,669 @@ async fn handle_hook(
     args: Arc<Args>,
     hook_args: HookArgs,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    println!("Get hook {:#?}", hook_args);
+    tracing::info!("Get hook {:?}", hook_args);
+    if args.no_patch {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&0),
+            StatusCode::OK,
+        ));
+    }
     let HookArgs { relative_path, .. } = hook_args;
+    if !is_below_root(Path::new(&relative_path)) {
+        tracing::warn!("hook for {} outside of the root", relative_path);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&0),
+            StatusCode::NOT_FOUND,
+        ));
+    }
     let root_path = args.root_path.clone().unwrap_or_default();
-    let path = root_path
-        .join(PathBuf::from(&args.relative_path))
-        .join(PathBuf::from(relative_path));
+    let relative = PathBuf::from(&args.relative_path).join(PathBuf::from(relative_path));
+    let path = root_path.join(&relative);
+    let patch_path = patch_path(&args, &relative);
 
     let task = async move {
-        let mut patch_path = path.clone();
-        let filename = patch_path.file_name().unwrap_or_default().to_os_string();
-        patch_path.set_file_name(format!(".{}", filename.to_string_lossy()));
-        patch_path.set_extension("v0.binpatch");
-        let patch = File::open(&patch_path).await;
+        let patch = open_fresh_patch(&path, &patch_path).await;
 
         match patch {
-            Ok(_) => {}
-            Err(_) => match generate_keyframes(path.clone(), patch_path).await {
-                Ok(_) => println!("generate_keyframes succeed"),
-                Err(e) => println!("Failed to generate keyframes {:?}", e),
+            Some(_) => {}
+            None => match generate_keyframes(&args, path.clone(), patch_path).await {
+                Ok(_) => tracing::info!("generate_keyframes succeed"),
+                Err(e) => tracing::error!("Failed to generate keyframes {:?}", e),
             },
         };
     };
     tokio::spawn(task);
-    Ok(warp::reply::json(&0))
+    Ok(warp::reply::with_status(
+        warp::reply::json(&0),
+        StatusCode::OK,
+    ))
 }
 
-#[paw::main]
-#[tokio::main]
-async fn main(args: Args) {
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_method("GET")
-        .allow_header("range");
-    let args = Arc::new(args);
+/// Parses the certificate and key up front, warp only panics with a bind
+/// error for them.
+fn check_tls(cert: &Path, key: &Path) -> Result<()> {
+    use rustls_pemfile::Item;
+
+    let read = |path: &Path| -> Result<Vec<Item>> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(rustls_pemfile::read_all(&mut reader)?)
+    };
+    if !read(cert)?
+        .iter()
+        .any(|item| matches!(item, Item::X509Certificate(_)))
+    {
+        return Err(anyhow!("no certificate found in {}", cert.display()));
+    }
+    if !read(key)?
+        .iter()
+        .any(|item| matches!(item, Item::RSAKey(_) | Item::PKCS8Key(_)))
+    {
+        return Err(anyhow!("no RSA or PKCS#8 key found in {}", key.display()));
+    }
+    Ok(())
+}
+
+/// Matches the segments of `--base-path`.
+fn base_path(base: &str) -> BoxedFilter<()> {
+    base.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
+}
+
+/// Every route of the server, with rejections turned into replies.
+fn routes(
+    args: Arc<Args>,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
     let get = warp::get()
+        .or(warp::head())
+        .unify()
+        .and(warp::method())
         .and(with_args(args.clone()))
         .and(warp::path::full())
+        .and(warp::query::<HashMap<String, String>>())
         .and(warp::header::headers_cloned())
         .and_then(handle_get);
-    let routes = warp::post()
-        .and(warp::path("space_webhook"))
-        .and(with_args(args))
+    // the path comes before the method, a rejected method outranks a
+    // missing file
+    let health = warp::path(args.health_path.trim_matches('/').to_string())
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| warp::reply::json(&Health { status: "ok" }));
+    let info = warp::path("api")
+        .and(warp::path("info"))
+        .and(warp::get())
+        .and(with_args(args.clone()))
+        .and(warp::path::tail())
+        .and_then(handle_info);
+    let keyframes = warp::path("api")
+        .and(warp::path("keyframes"))
+        .and(warp::get())
+        .and(with_args(args.clone()))
+        .and(warp::path::tail())
+        .and_then(handle_keyframes);
+    let chapters = warp::path("chapters")
+        .and(warp::get())
+        .and(with_args(args.clone()))
+        .and(warp::path::tail())
+        .and_then(handle_chapters);
+    let mounted = health.or(info).or(keyframes).or(chapters);
+    #[cfg(feature = "metrics")]
+    let mounted = mounted.or(warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_args(args.clone()))
+        .and_then(handle_metrics));
+    warp::path("space_webhook")
+        .and(warp::post())
+        .and(with_args(args.clone()))
         .and(warp::body::json())
         .and_then(handle_hook)
+        .or(base_path(&args.base_path).and(mounted))
         .or(get)
-        .with(cors);
-    warp::serve(routes).run(([0, 0, 0, 0], 3040)).await;
+        .recover(handle_rejection)
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.config_example {
+        if let Err(e) = std::fs::write(path, config::EXAMPLE) {
+            eprintln!("failed to write {}: {:?}", path.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = args.config.clone() {
+        let config = config::Config::load(&path);
+        if let Err(e) = config.and_then(|config| config.apply(&mut args, &matches)) {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    }
+    tracing_subscriber::fmt()
+        .with_max_level(args.log_level)
+        .with_writer(std::io::stderr)
+        .init();
+    if let Some(command) = args.command.take() {
+        let result = match command {
+            Command::Inspect { file } => inspect(&file).map(|_| 0),
+            Command::Patch { all, jobs, path } => patch(&args, path, all, jobs).await.map(|_| 0),
+            Command::Check { file } => check(&file),
+        };
+        match result {
+            Ok(0) => {}
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let cors = warp::cors()
+        .allow_methods(vec!["GET", "HEAD"])
+        .allow_header("range")
+        .allow_headers(args.cors_header.clone());
+    let cors = if args.cors_origin.is_empty() {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(args.cors_origin.iter().map(String::as_str))
+    };
+    let addr = (args.bind, args.port);
+    let tls = args.tls_cert.clone().zip(args.tls_key.clone());
+    if let Some((cert, key)) = &tls {
+        if let Err(e) = check_tls(cert, key) {
+            tracing::error!("invalid TLS configuration: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+    args.client = reqwest::Client::builder()
+        .user_agent(SERVER)
+        .build()
+        .expect("the TLS backend is compiled in");
+    if args.metrics {
+        #[cfg(feature = "metrics")]
+        {
+            args.recorder = Some(Metrics::new().expect("metrics are registered once"));
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            tracing::error!("--metrics needs flv-keyframes built with the metrics feature");
+            std::process::exit(1);
+        }
+    }
+    args.patch_cache = NonZeroUsize::new(args.patch_cache_entries).map(PatchCache::new);
+    let args = Arc::new(args);
+    if args.watch {
+        let args = args.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch(args).await {
+                tracing::error!("watching stopped: {:?}", e);
+            }
+        });
+    }
+    let recorder = args.clone();
+    let routes = warp::any()
+        .map(new_request_id)
+        .and(routes(args))
+        .map(|id: String, reply| warp::reply::with_header(reply, "X-Request-ID", id))
+        .map(|reply| warp::reply::with_header(reply, "Server", SERVER))
+        .with(cors)
+        // rejections only become responses at the end, count them too
+        .with(warp::log::custom(move |info| {
+            count_request(&recorder, info.status())
+        }))
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                "request",
+                id = tracing::field::Empty,
+                method = %info.method(),
+                path = %info.path()
+            )
+        }));
+    tracing::info!("starting flv-keyframes {}", VERSION);
+    match tls {
+        Some((cert, key)) => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .run(addr)
+                .await
+        }
+        None => warp::serve(routes).run(addr).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("flv-keyframes-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_args(flags: &[&str]) -> Arc<Args> {
+        let args = std::iter::once("flv-keyframes").chain(flags.iter().copied());
+        Arc::new(Args::try_parse_from(args).unwrap())
+    }
+
+    /// A root with a copy of the sample fixture, and the patched sample.
+    fn sample_root(name: &str) -> (PathBuf, Vec<u8>) {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let dir = temp_dir(name);
+        std::fs::copy(fixtures.join("sample.flv"), dir.join("sample.flv")).unwrap();
+        let patched = std::fs::read(fixtures.join("sample.patched.flv")).unwrap();
+        (dir, patched)
+    }
+
+    #[tokio::test]
+    async fn serves_byte_ranges() {
+        let (dir, patched) = sample_root("ranges");
+        let routes = routes(test_args(&["--root-path", dir.to_str().unwrap()]));
+        let len = patched.len();
+
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        assert_eq!(reply.headers()["Accept-Ranges"], "bytes");
+        assert_eq!(reply.headers()["Content-Length"], len.to_string().as_str());
+        assert!(reply.headers().get("Content-Range").is_none());
+        assert_eq!(reply.body().as_ref(), &patched[..]);
+
+        for (range, start, end) in &[
+            ("bytes=0-0", 0, 0),
+            ("bytes=-10", len - 10, len - 1),
+            ("bytes=270-", 270, len - 1),
+            ("bytes=270-100000", 270, len - 1),
+        ] {
+            let reply = warp::test::request()
+                .path("/sample.flv")
+                .header("Range", *range)
+                .reply(&routes)
+                .await;
+            assert_eq!(reply.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            assert_eq!(reply.headers()["Accept-Ranges"], "bytes");
+            assert_eq!(
+                reply.headers()["Content-Range"],
+                format!("bytes {}-{}/{}", start, end, len).as_str(),
+                "{}",
+                range
+            );
+            assert_eq!(reply.body().as_ref(), &patched[*start..=*end], "{}", range);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_multiple_ranges_as_multipart() {
+        let (dir, patched) = sample_root("multipart");
+        let routes = routes(test_args(&["--root-path", dir.to_str().unwrap()]));
+
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .header("Range", "bytes=0-9,20-29")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            reply.headers()["Content-Type"],
+            format!("multipart/byteranges; boundary={}", MULTIPART_BOUNDARY).as_str()
+        );
+        let body = reply.body();
+        assert_eq!(
+            reply.headers()["Content-Length"],
+            body.len().to_string().as_str()
+        );
+        let mut expected = vec![];
+        for (start, end) in &[(0, 9), (20, 29)] {
+            expected.extend_from_slice(
+                format!(
+                    "\r\n--{}\r\nContent-Type: video/x-flv\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    MULTIPART_BOUNDARY,
+                    start,
+                    end,
+                    patched.len()
+                )
+                .as_bytes(),
+            );
+            expected.extend_from_slice(&patched[*start..=*end]);
+        }
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY).as_bytes());
+        assert_eq!(body.as_ref(), &expected[..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn if_range_needs_the_current_etag() {
+        let (dir, patched) = sample_root("if-range");
+        let routes = routes(test_args(&["--root-path", dir.to_str().unwrap()]));
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .reply(&routes)
+            .await;
+        let etag = reply.headers()["ETag"].clone();
+
+        // an older version of the file gets the whole new one
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .header("Range", "bytes=0-9")
+            .header("If-Range", "\"stale\"")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        assert_eq!(reply.body().as_ref(), &patched[..]);
+
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .header("Range", "bytes=0-9")
+            .header("If-Range", etag)
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(reply.body().as_ref(), &patched[..10]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn conditional_requests_get_304() {
+        let (dir, _) = sample_root("conditional");
+        let routes = routes(test_args(&["--root-path", dir.to_str().unwrap()]));
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let etag = reply.headers()["ETag"].clone();
+        let last_modified = reply.headers()["Last-Modified"].clone();
+
+        for (name, value) in &[
+            ("If-None-Match", etag.clone()),
+            ("If-Modified-Since", last_modified),
+        ] {
+            let reply = warp::test::request()
+                .path("/sample.flv")
+                .header(*name, value)
+                .reply(&routes)
+                .await;
+            assert_eq!(reply.status(), StatusCode::NOT_MODIFIED, "{}", name);
+            assert_eq!(reply.headers()["ETag"], etag);
+            assert!(reply.body().is_empty());
+        }
+        let reply = warp::test::request()
+            .path("/sample.flv")
+            .header("If-None-Match", "\"other\"")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_ranges_get_416() {
+        let (dir, patched) = sample_root("unsatisfiable");
+        let routes = routes(test_args(&["--root-path", dir.to_str().unwrap()]));
+        for range in &["bytes=1000-", "bytes=282-300"] {
+            let reply = warp::test::request()
+                .path("/sample.flv")
+                .header("Range", *range)
+                .reply(&routes)
+                .await;
+            assert_eq!(
+                reply.status(),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "{}",
+                range
+            );
+            assert_eq!(
+                reply.headers()["Content-Range"],
+                format!("bytes */{}", patched.len()).as_str()
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn api_follows_the_served_file() {
+        let (dir, _) = sample_root("api");
+        let root = dir.to_str().unwrap();
+        let args = test_args(&["--root-path", root, "--max-file-size", "100"]);
+        let patch = patch_path(&args, Path::new("sample.flv"));
+        let reply = warp::test::request()
+            .path("/api/info/sample.flv")
+            .reply(&routes(args))
+            .await;
+        // too large to patch, or to scan for its keyframes
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+        assert!(!patch.exists());
+
+        let reply = warp::test::request()
+            .path("/api/info/sample.flv")
+            .reply(&routes(test_args(&["--root-path", root])))
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        assert!(patch.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strips_the_base_path() {
+        assert_eq!(strip_base_path("", "/a.flv"), Some("a.flv"));
+        assert_eq!(strip_base_path("/", "/"), Some(""));
+        assert_eq!(strip_base_path("/media", "/media/a/b.flv"), Some("a/b.flv"));
+        assert_eq!(strip_base_path("media/", "/media/"), Some(""));
+        assert_eq!(strip_base_path("/media", "/media"), None);
+        assert_eq!(strip_base_path("/media", "/mediafile.flv"), None);
+        assert_eq!(strip_base_path("/media", "/a.flv"), None);
+        assert_eq!(strip_base_path("", "a.flv"), None);
+    }
+
+    #[test]
+    fn attachment_names() {
+        assert_eq!(
+            content_disposition(Path::new("dir/live 1.flv")),
+            "attachment; filename=\"live 1.flv\""
+        );
+        assert_eq!(
+            content_disposition(Path::new("a\"b\\c%d.flv")),
+            "attachment; filename=\"a_b_c_d.flv\""
+        );
+        assert_eq!(
+            content_disposition(Path::new("直播.flv")),
+            "attachment; filename=\"__.flv\""
+        );
+        assert_eq!(
+            content_disposition(Path::new("")),
+            "attachment; filename=\"\""
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_below_the_base_path() {
+        let (dir, _) = sample_root("base-path");
+        let routes = routes(test_args(&[
+            "--root-path",
+            dir.to_str().unwrap(),
+            "--base-path",
+            "/media/flv/",
+        ]));
+        for (path, status) in &[
+            ("/media/flv/sample.flv", StatusCode::OK),
+            ("/media/flv/healthz", StatusCode::OK),
+            ("/media/flv/api/info/sample.flv", StatusCode::OK),
+            ("/media/flv/api/keyframes/sample.flv", StatusCode::OK),
+            ("/media/flv/chapters/sample.flv.vtt", StatusCode::OK),
+            ("/sample.flv", StatusCode::NOT_FOUND),
+            ("/healthz", StatusCode::NOT_FOUND),
+            ("/api/info/sample.flv", StatusCode::NOT_FOUND),
+            ("/chapters/sample.flv.vtt", StatusCode::NOT_FOUND),
+        ] {
+            let reply = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(reply.status(), *status, "{}", path);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_paths_leaving_the_root() {
+        let dir = temp_dir("traversal");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("inside.txt"), "inside").unwrap();
+        let routes = routes(test_args(&[
+            "--root-path",
+            root.to_str().unwrap(),
+            "--pass-through-non-flv",
+        ]));
+
+        let reply = warp::test::request()
+            .path("/inside.txt")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        for path in &[
+            "/..%2Fsecret.txt",
+            "/%2e%2e/secret.txt",
+            "/chapters/..%2Fsecret.flv.vtt",
+            "/api/info/..%2Fsecret.flv",
+            "/api/keyframes/..%2Fsecret.flv",
+        ] {
+            let reply = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(reply.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        let reply = warp::test::request()
+            .method("POST")
+            .path("/space_webhook")
+            .json(&serde_json::json!({
+                "EventRandomId": "id",
+                "RoomId": 1,
+                "Name": "name",
+                "Title": "title",
+                "RelativePath": "../secret.flv",
+                "FileSize": 6,
+                "StartRecordTime": "",
+                "EndRecordTime": "",
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn does_not_list_directories_outside_the_root() {
+        let dir = temp_dir("listing");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let routes = routes(test_args(&[
+            "--root-path",
+            root.to_str().unwrap(),
+            "--list-directory",
+        ]));
+
+        let reply = warp::test::request().path("/").reply(&routes).await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        for path in &["/%2e%2e/", "/..%2F", "/%2Ftmp/"] {
+            let reply = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(reply.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(mime_for_path(Path::new("a/video.flv")), "video/x-flv");
+        assert_eq!(mime_for_path(Path::new("VIDEO.FLV")), "video/x-flv");
+        assert_eq!(mime_for_path(Path::new("page.html")), "text/html");
+        assert_eq!(
+            mime_for_path(Path::new("no_extension")),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            mime_for_path(Path::new("unknown.zzzz")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn bool_flags_from_env() {
+        use clap::builder::TypedValueParser;
+
+        // what the FLV_* variables of the bool flags go through, without
+        // setting one under the other tests parsing `Args`
+        let command = clap::Command::new("flv-keyframes");
+        let parser = BoolishValueParser::new();
+        for (value, expected) in &[
+            ("1", true),
+            ("yes", true),
+            ("TRUE", true),
+            ("on", true),
+            ("off", false),
+            ("0", false),
+        ] {
+            let parsed = parser.parse_ref(&command, None, std::ffi::OsStr::new(value));
+            assert_eq!(parsed.unwrap(), *expected, "{}", value);
+        }
+        assert!(parser
+            .parse_ref(&command, None, std::ffi::OsStr::new("maybe"))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn replies_503_when_the_patch_times_out() {
+        // the remote file never sends more than its FLV header until the
+        // reply is checked
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let released = Arc::new(std::sync::Mutex::new(Some(released)));
+        let head = warp::head().map(|| {
+            warp::http::Response::builder()
+                .header("Content-Length", "1000")
+                .body(vec![])
+                .unwrap()
+        });
+        let get = warp::get().map(move || {
+            let released = released.lock().unwrap().take().unwrap();
+            let header = futures::stream::once(future::ready(Ok::<_, std::io::Error>(
+                Bytes::from_static(b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00"),
+            )));
+            let rest = futures::stream::once(async move {
+                let _ = released.await;
+                Ok(Bytes::new())
+            });
+            warp::hyper::Response::new(warp::hyper::Body::wrap_stream(header.chain(rest)))
+        });
+        let (addr, server) = warp::serve(head.or(get)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let patch_dir = std::env::temp_dir().join("flv-keyframes-timeout-test");
+        let mut args = Args::try_parse_from([
+            "flv-keyframes",
+            "--remote-base-url",
+            &format!("http://{}", addr),
+            "--patch-dir",
+            patch_dir.to_str().unwrap(),
+            "--timeout-ms",
+            "50",
+        ])
+        .unwrap();
+        args.client = reqwest::Client::new();
+        let reply = warp::test::request()
+            .path("/slow.flv")
+            .reply(&routes(Arc::new(args)))
+            .await;
+        let _ = release.send(());
+        assert_eq!(reply.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply.headers()["Retry-After"], "1");
+    }
 }
@@ -0,0 +1,53 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Counters exposed at `/metrics` with `--metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// by response status
+    pub requests: IntCounterVec,
+    pub patch_generation: Histogram,
+    pub cache_hits: IntCounter,
+    pub cache_misses: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("flv_requests_total", "HTTP requests by response status"),
+            &["status"],
+        )?;
+        // a few milliseconds for small files up to minutes for remote ones
+        let patch_generation = Histogram::with_opts(
+            HistogramOpts::new(
+                "flv_patch_generation_duration_seconds",
+                "time taken to generate a patch",
+            )
+            .buckets(prometheus::exponential_buckets(0.01, 4.0, 8)?),
+        )?;
+        let cache_hits = IntCounter::new("flv_patch_cache_hits_total", "stored patches used")?;
+        let cache_misses = IntCounter::new(
+            "flv_patch_cache_misses_total",
+            "patches generated because none was stored",
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(patch_generation.clone()))?;
+        registry.register(Box::new(cache_hits.clone()))?;
+        registry.register(Box::new(cache_misses.clone()))?;
+        Ok(Metrics {
+            registry,
+            requests,
+            patch_generation,
+            cache_hits,
+            cache_misses,
+        })
+    }
+    /// The Prometheus text format of every metric.
+    pub fn render(&self) -> prometheus::Result<String> {
+        let mut out = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut out)?;
+        Ok(String::from_utf8(out).expect("the text format is UTF-8"))
+    }
+}
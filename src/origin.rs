@@ -0,0 +1,371 @@
+use bytes::{Buf, Bytes};
+use futures::{
+    ready,
+    stream::{Stream, StreamExt},
+};
+use std::{
+    future::Future,
+    io::{self, Read, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeek},
+};
+
+/// Where the unpatched bytes of a served file come from.
+#[derive(Clone)]
+pub enum Origin {
+    Local(PathBuf),
+    Remote(RemoteFile),
+}
+
+impl Origin {
+    /// Path of the file, the one relative to `--remote-base-url` for remote files.
+    pub fn path(&self) -> &Path {
+        match self {
+            Origin::Local(path) => path,
+            Origin::Remote(remote) => &remote.relative,
+        }
+    }
+    /// The path of a local file or the URL of a remote one, for logs.
+    pub fn location(&self) -> String {
+        match self {
+            Origin::Local(path) => path.display().to_string(),
+            Origin::Remote(remote) => remote.url().to_string(),
+        }
+    }
+    pub async fn open(&self) -> io::Result<OriginReader> {
+        Ok(match self {
+            Origin::Local(path) => OriginReader::Local(File::open(path).await?),
+            Origin::Remote(remote) => OriginReader::Remote(remote.reader()),
+        })
+    }
+}
+
+/// A file behind a HTTP server supporting range requests.
+#[derive(Clone)]
+pub struct RemoteFile {
+    client: reqwest::Client,
+    url: String,
+    relative: PathBuf,
+    len: u64,
+    etag: Option<String>,
+}
+
+fn to_io_error(e: reqwest::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl RemoteFile {
+    /// Asks the server for the length of the file at `url`.
+    pub async fn head(
+        client: reqwest::Client,
+        url: String,
+        relative: PathBuf,
+    ) -> io::Result<RemoteFile> {
+        let response = client
+            .head(&url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(to_io_error)?;
+        // the body of a HEAD response is empty, its length is in the header
+        let len = response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "no Content-Length in response")
+            })?;
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        Ok(RemoteFile {
+            client,
+            url,
+            relative,
+            len,
+            etag,
+        })
+    }
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Changes when the file does, as far as its length and `ETag` tell.
+    pub fn version(&self) -> String {
+        format!("{} {}", self.len, self.etag.as_deref().unwrap_or_default())
+    }
+    /// Sends a GET for the whole file, to be read once from the start on a
+    /// blocking thread.
+    pub async fn download(&self) -> io::Result<BlockingBody> {
+        let response = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(to_io_error)?;
+        Ok(BlockingBody {
+            body: Box::pin(response.bytes_stream()),
+            chunk: Bytes::new(),
+        })
+    }
+    pub fn reader(&self) -> RemoteReader {
+        RemoteReader {
+            file: self.clone(),
+            pos: 0,
+            request: None,
+            body: None,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+type ResponseFuture = Pin<Box<dyn Future<Output = reqwest::Result<reqwest::Response>> + Send>>;
+type BodyStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+/// A response body as a plain `Read`, every read waits for the next chunk.
+/// Only for blocking threads, the connection itself is driven by the runtime.
+pub struct BlockingBody {
+    body: BodyStream,
+    /// received but not read yet
+    chunk: Bytes,
+}
+
+impl Read for BlockingBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match futures::executor::block_on(self.body.next()) {
+                Some(chunk) => self.chunk = chunk.map_err(to_io_error)?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
+
+/// Reads a `RemoteFile` with one `Range: bytes=<pos>-` request per seek,
+/// the request is only sent on the first read after it.
+pub struct RemoteReader {
+    file: RemoteFile,
+    pos: u64,
+    request: Option<ResponseFuture>,
+    body: Option<BodyStream>,
+    /// received but not read yet
+    chunk: Bytes,
+}
+
+impl AsyncRead for RemoteReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.chunk.is_empty() {
+                let n = buf.len().min(self.chunk.len());
+                buf[..n].copy_from_slice(&self.chunk[..n]);
+                self.chunk.advance(n);
+                self.pos += n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            if self.pos >= self.file.len {
+                return Poll::Ready(Ok(0));
+            }
+            if let Some(body) = &mut self.body {
+                match ready!(body.as_mut().poll_next(cx)) {
+                    Some(chunk) => self.chunk = chunk.map_err(to_io_error)?,
+                    None => return Poll::Ready(Ok(0)),
+                }
+                continue;
+            }
+            if self.request.is_none() {
+                let request = self
+                    .file
+                    .client
+                    .get(&self.file.url)
+                    .header(reqwest::header::RANGE, format!("bytes={}-", self.pos))
+                    .send();
+                self.request = Some(Box::pin(request));
+            }
+            let response = ready!(self.request.as_mut().unwrap().as_mut().poll(cx));
+            self.request = None;
+            let response = response
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(to_io_error)?;
+            // a server ignoring the range sends the file from the start
+            if response.status() != reqwest::StatusCode::PARTIAL_CONTENT && self.pos != 0 {
+                return Poll::Ready(Err(io::Error::other(format!(
+                    "{} does not support range requests",
+                    self.file.url
+                ))));
+            }
+            self.body = Some(Box::pin(response.bytes_stream()));
+        }
+    }
+}
+
+impl AsyncSeek for RemoteReader {
+    fn start_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<io::Result<()>> {
+        let pos = match position {
+            SeekFrom::Start(i) => Some(i),
+            SeekFrom::Current(i) => self.pos.checked_add_signed(i),
+            SeekFrom::End(i) => self.file.len.checked_add_signed(i),
+        };
+        // the position stays where it was, like for `PatchedReader`
+        let pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        if pos != self.pos {
+            self.pos = pos;
+            self.request = None;
+            self.body = None;
+            self.chunk = Bytes::new();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+/// Reader of an `Origin`, to be patched with `Patch::patch_reader`.
+pub enum OriginReader {
+    Local(File),
+    Remote(RemoteReader),
+}
+
+impl AsyncRead for OriginReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            OriginReader::Local(file) => Pin::new(file).poll_read(cx, buf),
+            OriginReader::Remote(remote) => Pin::new(remote).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncSeek for OriginReader {
+    fn start_seek(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OriginReader::Local(file) => Pin::new(file).start_seek(cx, position),
+            OriginReader::Remote(remote) => Pin::new(remote).start_seek(cx, position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match self.get_mut() {
+            OriginReader::Local(file) => Pin::new(file).poll_complete(cx),
+            OriginReader::Remote(remote) => Pin::new(remote).poll_complete(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::prelude::*;
+    use warp::Filter;
+
+    const DATA: &[u8] = b"0123456789abcdefghij";
+
+    /// Serves `DATA` at /ranged from the start of `Range: bytes=<start>-`,
+    /// recording the ranges asked for, and at /whole ignoring ranges.
+    fn serve() -> (String, Arc<Mutex<Vec<String>>>) {
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let seen = ranges.clone();
+        let ranged = warp::path("ranged")
+            .and(warp::header::<String>("range"))
+            .map(move |range: String| {
+                let start: usize = range
+                    .trim_start_matches("bytes=")
+                    .trim_end_matches('-')
+                    .parse()
+                    .unwrap();
+                seen.lock().unwrap().push(range);
+                warp::http::Response::builder()
+                    .status(206)
+                    .header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, DATA.len() - 1, DATA.len()),
+                    )
+                    .body(DATA[start..].to_vec())
+                    .unwrap()
+            });
+        let whole = warp::path("whole").map(|| DATA.to_vec());
+        let (addr, server) = warp::serve(ranged.or(whole)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), ranges)
+    }
+
+    fn remote_file(url: String) -> RemoteFile {
+        RemoteFile {
+            client: reqwest::Client::new(),
+            url,
+            relative: PathBuf::from("file.flv"),
+            len: DATA.len() as u64,
+            etag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_with_a_range_after_seeking() {
+        let (base, ranges) = serve();
+        let mut reader = remote_file(format!("{}/ranged", base)).reader();
+        let mut head = [0u8; 4];
+        reader.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"0123");
+
+        assert_eq!(reader.seek(SeekFrom::Start(10)).await.unwrap(), 10);
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"abcdefghij");
+        assert_eq!(*ranges.lock().unwrap(), ["bytes=0-", "bytes=10-"]);
+
+        // seeking before the start fails and keeps the position
+        assert!(reader.seek(SeekFrom::Current(-21)).await.is_err());
+        assert_eq!(reader.seek(SeekFrom::Current(0)).await.unwrap(), 20);
+    }
+
+    #[tokio::test]
+    async fn fails_when_a_range_is_ignored() {
+        let (base, _) = serve();
+        let file = remote_file(format!("{}/whole", base));
+        // from the start the whole file is what was asked for
+        let mut all = vec![];
+        file.reader().read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, DATA);
+
+        let mut reader = file.reader();
+        reader.seek(SeekFrom::Start(5)).await.unwrap();
+        let e = reader.read(&mut [0u8; 4]).await.unwrap_err();
+        assert!(e.to_string().contains("range requests"), "{}", e);
+    }
+}
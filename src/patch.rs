@@ -1,32 +1,159 @@
+use crate::error::FlvError;
 use bytes::{Buf, Bytes, BytesMut};
 use futures::{
     ready,
     stream::{self, Stream},
+    task::noop_waker_ref,
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    io::{self, SeekFrom},
+    convert::TryFrom,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::io::{AsyncRead, AsyncSeek};
 use tokio::prelude::*;
+use tokio::{
+    fs::File,
+    io::{AsyncBufRead, AsyncRead, AsyncSeek},
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Patch {
+pub struct PatchRegion {
     pub origin_pos: u64,
     pub origin_size: u64,
+    #[cfg_attr(feature = "json-patch", serde(with = "base64_bytes"))]
     pub patched: Vec<u8>,
 }
 
+/// `patched` as a base64 string, JSON arrays of numbers are too long to read.
+#[cfg(feature = "json-patch")]
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        base64::decode(&s).map_err(D::Error::custom)
+    }
+}
+
+impl PatchRegion {
+    fn origin_end(&self) -> u64 {
+        self.origin_pos + self.origin_size
+    }
+    fn patched_len(&self) -> u64 {
+        self.patched.len() as u64
+    }
+}
+
+/// A set of non-overlapping regions, sorted by `origin_pos`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(try_from = "PatchFile", into = "PatchFile")]
+pub struct Patch {
+    regions: Vec<PatchRegion>,
+}
+
+/// On-disk representation of `Patch`, the variant is the format version.
+#[derive(Serialize, Deserialize)]
+enum PatchFile {
+    V1(Vec<PatchRegion>),
+}
+
+impl TryFrom<PatchFile> for Patch {
+    type Error = io::Error;
+
+    fn try_from(file: PatchFile) -> io::Result<Patch> {
+        match file {
+            PatchFile::V1(regions) => Patch::new(regions),
+        }
+    }
+}
+
+impl From<Patch> for PatchFile {
+    fn from(patch: Patch) -> PatchFile {
+        PatchFile::V1(patch.regions)
+    }
+}
+
+/// A serialized `Patch` compressed with zstd, for storing on disk.
+#[derive(Debug, Clone)]
+pub struct CompressedPatch {
+    data: Vec<u8>,
+}
+
+impl CompressedPatch {
+    /// Wraps bytes previously taken from `as_bytes`.
+    pub fn from_bytes(data: Vec<u8>) -> CompressedPatch {
+        CompressedPatch { data }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+    /// Whether `data` starts like a zstd frame, a plain serialized patch never does.
+    pub fn is_compressed(data: &[u8]) -> bool {
+        data.starts_with(&zstd::zstd_safe::MAGICNUMBER.to_le_bytes())
+    }
+    pub fn decompress(&self) -> io::Result<Patch> {
+        let data = zstd::decode_all(&self.data[..])?;
+        Patch::from_bytes(&data)
+    }
+    /// Writes the compressed patch to `path`, see `Patch::save`.
+    pub async fn save(&self, path: &Path) -> crate::Result<()> {
+        write_atomic(path, &self.data)
+            .await
+            .map_err(|source| patch_file_error(path, source))
+    }
+}
+
+fn patch_file_error(path: &Path, source: io::Error) -> FlvError {
+    FlvError::PatchFile {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Writes aside and renames, so a crash never leaves a partial file behind.
+async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut file = File::create(&tmp_path).await?;
+    let written = async {
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if written.is_err() {
+        // the error of the write is the one worth reporting
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    written
+}
+
+#[derive(Debug, Clone)]
 pub struct PatchedReader<R> {
     reader: R,
     reader_pos: u64,
     patch: Patch,
     offset: u64,
     origin_length: u64,
+    /// data from `buf_start` on for `AsyncBufRead`, empty until it is used
+    buf: Vec<u8>,
+    buf_start: u64,
 }
 
+/// Bytes read at once by `AsyncBufRead::poll_fill_buf`.
+const BUF_SIZE: usize = 8 * 1024;
+
 impl<R> AsyncSeek for PatchedReader<R>
 where
     R: AsyncSeek + Send + 'static + Unpin,
@@ -36,15 +163,18 @@ where
         _cx: &mut Context<'_>,
         position: SeekFrom,
     ) -> Poll<io::Result<()>> {
-        match position {
-            SeekFrom::Start(i) => {
-                self.offset = i;
-            }
-            SeekFrom::Current(i) => {
-                self.offset = (self.offset as i64 + i) as u64;
-            }
-            SeekFrom::End(i) => self.offset = (self.len() as i64 + i) as u64,
-        }
+        let offset = match position {
+            SeekFrom::Start(i) => Some(i),
+            SeekFrom::Current(i) => self.offset.checked_add_signed(i),
+            SeekFrom::End(i) => self.len().checked_add_signed(i),
+        };
+        // the offset stays where it was, like for `std::fs::File`
+        self.offset = offset.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
         Poll::Ready(Ok(()))
     }
 
@@ -63,44 +193,55 @@ where
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
         let off = self.offset;
-        let patch = &self.patch;
-        let (read_from, readable) = if off < patch.origin_pos {
-            // before patch
-            (StartPoint::Origin(off), patch.origin_pos - off)
-        } else if off >= (patch.origin_pos + self.patched_len()) {
-            // after patch
-            let off = off - self.patched_len() + patch.origin_size;
-            (StartPoint::Origin(off), self.len() - off)
-        } else {
-            // in patch
-            let off = off - patch.origin_pos;
-            (StartPoint::Patch(off as usize), self.patched_len() - off)
-        };
-        let read_size = buf.len().min(readable as usize);
-        let result = match read_from {
-            StartPoint::Origin(off) => {
-                if self.reader_pos != off {
-                    ready!(Pin::new(&mut self.reader).start_seek(cx, SeekFrom::Start(off)))?;
-                    self.reader_pos = ready!(Pin::new(&mut self.reader).poll_complete(cx))?;
-                }
-                let read = ready!(Pin::new(&mut self.reader).poll_read(cx, &mut buf[..read_size]))?;
-                self.reader_pos += read as u64;
-                read
-            }
-            StartPoint::Patch(off) => {
-                &mut buf[..read_size].copy_from_slice(&patch.patched[off..off + read_size]);
-                read_size
-            }
-        };
+        let result = ready!(self.poll_read_at(cx, off, buf))?;
         self.offset += result as u64;
         Poll::Ready(Ok(result))
     }
 }
 
+impl<R> AsyncBufRead for PatchedReader<R>
+where
+    R: AsyncRead + AsyncSeek + Send + 'static + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let off = this.offset;
+        let buffered = off >= this.buf_start && off < this.buf_start + this.buf.len() as u64;
+        if !buffered {
+            let mut buf = std::mem::take(&mut this.buf);
+            buf.resize(BUF_SIZE, 0);
+            // a single read never crosses into or out of a region
+            let result = this.poll_read_at(cx, off, &mut buf);
+            buf.truncate(match result {
+                Poll::Ready(Ok(read)) => read,
+                _ => 0,
+            });
+            // kept even when nothing was read, to reuse the allocation
+            this.buf = buf;
+            this.buf_start = off;
+            ready!(result)?;
+        }
+        Poll::Ready(Ok(&this.buf[(off - this.buf_start) as usize..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.offset += amt as u64;
+    }
+}
+
+impl<R> PatchedReader<R> {
+    /// Gives back the origin reader, at whatever position the last read
+    /// left it.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
 #[derive(Debug)]
 enum StartPoint {
     Origin(u64),
-    Patch(usize),
+    /// index of the region, offset in its patched data
+    Patch(usize, usize),
 }
 
 impl<R> PatchedReader<R>
@@ -116,23 +257,156 @@ where
             patch,
             offset: 0,
             origin_length,
+            buf: vec![],
+            buf_start: 0,
         })
     }
     pub fn len(&self) -> u64 {
-        self.origin_length + (self.patched_len()) - self.patch.origin_size
+        (self.origin_length as i64 + self.patch.size_delta()) as u64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Current position in the patched stream.
+    pub fn tell(&self) -> u64 {
+        self.offset
+    }
+    /// Bytes left until the end of the patched stream.
+    pub fn remaining(&self) -> u64 {
+        self.len().saturating_sub(self.offset)
+    }
+    /// Reads at `off` of the patched stream, without moving to it.
+    fn poll_read_at(
+        &mut self,
+        cx: &mut Context<'_>,
+        off: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead,
+    {
+        let (read_from, readable) = self.get_point(off);
+        let read_size = buf.len().min(readable as usize);
+        Poll::Ready(Ok(match read_from {
+            StartPoint::Origin(off) => {
+                if self.reader_pos != off {
+                    ready!(Pin::new(&mut self.reader).start_seek(cx, SeekFrom::Start(off)))?;
+                    self.reader_pos = ready!(Pin::new(&mut self.reader).poll_complete(cx))?;
+                }
+                let read = ready!(Pin::new(&mut self.reader).poll_read(cx, &mut buf[..read_size]))?;
+                self.reader_pos += read as u64;
+                read
+            }
+            StartPoint::Patch(index, off) => {
+                let patched = &self.patch.regions[index].patched;
+                buf[..read_size].copy_from_slice(&patched[off..off + read_size]);
+                read_size
+            }
+        }))
+    }
+    /// Maps an offset of the patched stream to where it should be read from,
+    /// along with how many bytes can be read there continuously.
+    fn get_point(&self, off: u64) -> (StartPoint, u64) {
+        // patched position minus origin position
+        let mut delta: i64 = 0;
+        for (index, region) in self.patch.regions.iter().enumerate() {
+            let start = (region.origin_pos as i64 + delta) as u64;
+            if off < start {
+                // before this region
+                return (StartPoint::Origin((off as i64 - delta) as u64), start - off);
+            }
+            let end = start + region.patched_len();
+            if off < end {
+                // in this region
+                return (StartPoint::Patch(index, (off - start) as usize), end - off);
+            }
+            delta += region.patched_len() as i64 - region.origin_size as i64;
+        }
+        // after all regions
+        (
+            StartPoint::Origin((off as i64 - delta) as u64),
+            self.len().saturating_sub(off),
+        )
+    }
+}
+
+/// Blocking `Read + Seek` over a patched in-memory file.
+#[derive(Debug, Clone)]
+pub struct SyncPatchedReader {
+    inner: PatchedReader<Cursor<Vec<u8>>>,
+}
+
+impl SyncPatchedReader {
+    pub fn new(input: Vec<u8>, patch: Patch) -> SyncPatchedReader {
+        let origin_length = input.len() as u64;
+        SyncPatchedReader {
+            inner: PatchedReader {
+                reader: Cursor::new(input),
+                reader_pos: 0,
+                patch,
+                offset: 0,
+                origin_length,
+                buf: vec![],
+                buf_start: 0,
+            },
+        }
+    }
+    pub fn len(&self) -> u64 {
+        self.inner.len()
     }
-    pub fn patched_len(&self) -> u64 {
-        self.patch.patched.len() as u64
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+    /// See `PatchedReader::tell`.
+    pub fn tell(&self) -> u64 {
+        self.inner.tell()
+    }
+    /// See `PatchedReader::remaining`.
+    pub fn remaining(&self) -> u64 {
+        self.inner.remaining()
+    }
+}
+
+/// Polls once, a `Cursor` never returns `Pending`.
+fn poll_now<T>(f: impl FnOnce(&mut Context<'_>) -> Poll<T>) -> T {
+    match f(&mut Context::from_waker(noop_waker_ref())) {
+        Poll::Ready(v) => v,
+        Poll::Pending => unreachable!("in-memory reader is never pending"),
+    }
+}
+
+impl Read for SyncPatchedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = Pin::new(&mut self.inner);
+        poll_now(|cx| inner.poll_read(cx, buf))
+    }
+}
+
+impl Seek for SyncPatchedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        poll_now(|cx| Pin::new(&mut self.inner).start_seek(cx, pos))?;
+        poll_now(|cx| Pin::new(&mut self.inner).poll_complete(cx))
     }
 }
 
-pub fn reader_stream<R>(mut reader: R) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+pub fn reader_stream<R>(reader: R) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+where
+    R: AsyncRead + Send + 'static + Unpin,
+{
+    reader_stream_with_chunk_size(reader, 2048)
+}
+
+/// Same as `reader_stream`, reading up to `chunk_size` bytes per item.
+pub fn reader_stream_with_chunk_size<R>(
+    mut reader: R,
+    chunk_size: usize,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>>
 where
     R: AsyncRead + Send + 'static + Unpin,
 {
     stream::poll_fn(move |cx| {
         let mut buf = BytesMut::new();
-        buf.reserve(2048);
+        buf.reserve(chunk_size);
         let n = match ready!(Pin::new(&mut reader).poll_read_buf(cx, &mut buf)) {
             Ok(n) => n,
             Err(e) => return Poll::Ready(Some(Err(e))),
@@ -145,10 +419,123 @@ where
 }
 
 impl Patch {
+    /// Builds a patch from regions, failing if any two of them overlap or one
+    /// ends past `i64::MAX`.
+    pub fn new(mut regions: Vec<PatchRegion>) -> io::Result<Patch> {
+        // offsets are shifted as i64 while reading
+        if let Some(region) = regions.iter().find(|r| {
+            r.origin_pos
+                .checked_add(r.origin_size)
+                .is_none_or(|end| end > i64::MAX as u64)
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "patch region at {} of {} bytes is out of range",
+                    region.origin_pos, region.origin_size
+                ),
+            ));
+        }
+        regions.sort_by_key(|r| r.origin_pos);
+        for pair in regions.windows(2) {
+            if pair[0].origin_end() > pair[1].origin_pos {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "patch region at {} overlaps region at {}",
+                        pair[0].origin_pos, pair[1].origin_pos
+                    ),
+                ));
+            }
+        }
+        Ok(Patch { regions })
+    }
+    /// Combines two patches of the same origin file into one, failing if a
+    /// region of `a` overlaps one of `b`. Insertions at the same position
+    /// keep `a`'s first.
+    pub fn merge(a: Patch, b: Patch) -> io::Result<Patch> {
+        let mut regions = a.regions;
+        regions.extend(b.regions);
+        Patch::new(regions)
+    }
+    pub fn regions(&self) -> &[PatchRegion] {
+        &self.regions
+    }
+    /// Serializes the patch for storing on disk, with bincode or as JSON with
+    /// the `json-patch` feature.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        #[cfg(not(feature = "json-patch"))]
+        let data =
+            bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        #[cfg(feature = "json-patch")]
+        let data = serde_json::to_vec(self).map_err(io::Error::from);
+        data
+    }
+    /// Reads a patch written by `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Patch> {
+        #[cfg(not(feature = "json-patch"))]
+        let patch =
+            bincode::deserialize(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        #[cfg(feature = "json-patch")]
+        let patch = serde_json::from_slice(data).map_err(io::Error::from);
+        patch
+    }
+    /// Reads a patch written by `to_bytes`, compressed or not.
+    pub async fn read_from<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<Patch> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data).await?;
+        if CompressedPatch::is_compressed(&data) {
+            CompressedPatch::from_bytes(data).decompress()
+        } else {
+            Patch::from_bytes(&data)
+        }
+    }
+    /// Reads the patch stored at `path` by `save` or `CompressedPatch::save`.
+    pub async fn from_file(path: &Path) -> crate::Result<Patch> {
+        let read = async { Patch::read_from(File::open(path).await?).await };
+        read.await.map_err(|source| patch_file_error(path, source))
+    }
+    /// Writes the patch to `path` with `to_bytes`, replacing the file at
+    /// once so readers never see half of it.
+    pub async fn save(&self, path: &Path) -> crate::Result<()> {
+        let write = async { write_atomic(path, &self.to_bytes()?).await };
+        write.await.map_err(|source| patch_file_error(path, source))
+    }
+    pub fn compress(&self) -> io::Result<CompressedPatch> {
+        Ok(CompressedPatch {
+            data: zstd::encode_all(&self.to_bytes()?[..], zstd::DEFAULT_COMPRESSION_LEVEL)?,
+        })
+    }
+    /// Applies the patch to an in-memory file.
+    pub fn apply_to_bytes(&self, input: &[u8]) -> Vec<u8> {
+        let clamp = |pos: u64| (pos as usize).min(input.len());
+        let mut out = Vec::with_capacity((input.len() as i64 + self.size_delta()).max(0) as usize);
+        let mut pos = 0;
+        for region in &self.regions {
+            out.extend_from_slice(&input[pos..clamp(region.origin_pos)]);
+            out.extend_from_slice(&region.patched);
+            pos = clamp(region.origin_end());
+        }
+        out.extend_from_slice(&input[pos..]);
+        out
+    }
+    /// How many bytes the patched file is longer than the origin.
+    pub fn size_delta(&self) -> i64 {
+        self.regions
+            .iter()
+            .map(|r| r.patched_len() as i64 - r.origin_size as i64)
+            .sum()
+    }
+    /// Whether applying the patch leaves any file unchanged, without reading it.
+    pub fn is_identity(&self) -> bool {
+        self.regions
+            .iter()
+            .all(|r| r.origin_size == 0 && r.patched.is_empty())
+    }
     pub async fn patch_reader<R>(&self, reader: R) -> io::Result<PatchedReader<R>>
     where
         R: AsyncRead + AsyncSeek + Send + 'static + Unpin,
     {
-        Ok(PatchedReader::new(reader, self.clone()).await?)
+        PatchedReader::new(reader, self.clone()).await
     }
 }
@@ -0,0 +1,96 @@
+use flv_keyframes::Patch;
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A patch read from disk, valid while its file keeps the same length and
+/// modification time.
+struct CachedPatch {
+    len: u64,
+    modified: SystemTime,
+    patch: Arc<Patch>,
+}
+
+/// The most recently served patches by patch path, with
+/// `--patch-cache-entries`.
+pub struct PatchCache {
+    entries: Mutex<LruCache<PathBuf, CachedPatch>>,
+}
+
+impl PatchCache {
+    pub fn new(capacity: NonZeroUsize) -> PatchCache {
+        PatchCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+    /// The patch read from `path`, if its file has not changed since.
+    pub fn get(&self, path: &Path, len: u64, modified: SystemTime) -> Option<Arc<Patch>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(cached) if cached.len == len && cached.modified == modified => {
+                Some(cached.patch.clone())
+            }
+            _ => None,
+        }
+    }
+    pub fn put(&self, path: PathBuf, len: u64, modified: SystemTime, patch: Arc<Patch>) {
+        self.entries.lock().unwrap().put(
+            path,
+            CachedPatch {
+                len,
+                modified,
+                patch,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn is_cached(cache: &PatchCache, path: &str, len: u64, modified: SystemTime) -> bool {
+        cache.get(Path::new(path), len, modified).is_some()
+    }
+
+    #[test]
+    fn rewritten_patches_miss() {
+        let cache = PatchCache::new(NonZeroUsize::new(4).unwrap());
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let patch = Arc::new(Patch::default());
+        cache.put("a.patch".into(), 100, modified, patch.clone());
+
+        let cached = cache.get(Path::new("a.patch"), 100, modified).unwrap();
+        assert!(Arc::ptr_eq(&cached, &patch));
+        assert!(!is_cached(&cache, "a.patch", 101, modified));
+        assert!(!is_cached(
+            &cache,
+            "a.patch",
+            100,
+            modified + Duration::from_millis(1)
+        ));
+        assert!(!is_cached(&cache, "b.patch", 100, modified));
+        // a miss keeps the entry for the file it was read from
+        assert!(is_cached(&cache, "a.patch", 100, modified));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = PatchCache::new(NonZeroUsize::new(2).unwrap());
+        let modified = SystemTime::UNIX_EPOCH;
+        for path in &["a.patch", "b.patch"] {
+            cache.put(path.into(), 100, modified, Arc::new(Patch::default()));
+        }
+        // a is used again, b goes first
+        assert!(is_cached(&cache, "a.patch", 100, modified));
+        cache.put("c.patch".into(), 100, modified, Arc::new(Patch::default()));
+        assert!(is_cached(&cache, "a.patch", 100, modified));
+        assert!(!is_cached(&cache, "b.patch", 100, modified));
+        assert!(is_cached(&cache, "c.patch", 100, modified));
+    }
+}
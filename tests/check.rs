@@ -0,0 +1,54 @@
+mod common;
+
+use common::flv_writer::{write_synthetic_flv, SyntheticFlvConfig};
+use flv_keyframes::{check_flv, read_flv_with_offsets, CheckIssue};
+use std::process::Command;
+
+/// A synthetic file whose pre tag sizes were never written.
+fn without_pre_tag_sizes() -> (Vec<u8>, usize) {
+    let mut input = write_synthetic_flv(&SyntheticFlvConfig::default());
+    let ends: Vec<_> = read_flv_with_offsets(&input[..])
+        .map(|tag| {
+            let (offset, tag) = tag.unwrap();
+            (offset + tag.tag_size as u64) as usize
+        })
+        .collect();
+    for &end in &ends {
+        input[end..end + 4].copy_from_slice(&[0; 4]);
+    }
+    (input, ends.len())
+}
+
+#[test]
+fn checks_tags_after_a_bad_pre_tag_size() {
+    let (input, tags) = without_pre_tag_sizes();
+    let report = check_flv(&input[..]);
+    assert_eq!(report.tag_count, tags);
+    assert_eq!(report.issues.len(), tags);
+    assert!(report
+        .issues
+        .iter()
+        .all(|issue| matches!(issue, CheckIssue::BadPreviousTagSize { found: 0, .. })));
+}
+
+/// The exit code of `flv-keyframes check` on `input`.
+fn check_exit_code(name: &str, input: &[u8]) -> i32 {
+    let path = std::env::temp_dir().join(format!("check_{}_{}.flv", name, std::process::id()));
+    std::fs::write(&path, input).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_flv-keyframes"))
+        .arg("check")
+        .arg(&path)
+        .output()
+        .unwrap()
+        .status;
+    std::fs::remove_file(&path).unwrap();
+    status.code().unwrap()
+}
+
+#[test]
+fn exit_codes() {
+    let input = write_synthetic_flv(&SyntheticFlvConfig::default());
+    assert_eq!(check_exit_code("valid", &input), 0);
+    assert_eq!(check_exit_code("warnings", &without_pre_tag_sizes().0), 1);
+    assert_eq!(check_exit_code("truncated", &input[..input.len() - 3]), 2);
+}
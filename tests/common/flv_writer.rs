@@ -0,0 +1,67 @@
+use super::tag_bytes;
+use flv_keyframes::FlvHeader;
+
+/// What goes in a file from `write_synthetic_flv`.
+#[derive(Debug, Clone)]
+pub struct SyntheticFlvConfig {
+    pub num_keyframes: usize,
+    /// spread evenly between the keyframes
+    pub num_audio_tags: usize,
+    pub keyframe_interval_ms: u32,
+    /// an `onMetaData` tag without keyframes in front of the other tags
+    pub include_script_tag: bool,
+}
+
+impl Default for SyntheticFlvConfig {
+    fn default() -> SyntheticFlvConfig {
+        SyntheticFlvConfig {
+            num_keyframes: 10,
+            num_audio_tags: 20,
+            keyframe_interval_ms: 1000,
+            include_script_tag: true,
+        }
+    }
+}
+
+/// A valid FLV with H.264 keyframes and AAC audio tags, the same for the
+/// same config.
+pub fn write_synthetic_flv(config: &SyntheticFlvConfig) -> Vec<u8> {
+    let mut out = FlvHeader {
+        version: 1,
+        has_audio: config.num_audio_tags > 0,
+        has_video: true,
+        reserved_flags: 0,
+        data_offset: 9,
+    }
+    .write_to_bytes();
+
+    let duration = config.num_keyframes as u32 * config.keyframe_interval_ms;
+    if config.include_script_tag {
+        let mut metadata =
+            b"\x02\x00\x0aonMetaData\x08\x00\x00\x00\x01\x00\x08duration\x00".to_vec();
+        metadata.extend_from_slice(&(duration as f64 / 1000.0).to_be_bytes());
+        metadata.extend_from_slice(b"\x00\x00\x09");
+        out.extend(tag_bytes(18, 0, &metadata));
+    }
+
+    let video = (0..config.num_keyframes).map(|i| (i as u32 * config.keyframe_interval_ms, 9));
+    let audio = (0..config.num_audio_tags).map(|i| {
+        (
+            (i as u64 * duration as u64 / config.num_audio_tags as u64) as u32,
+            8,
+        )
+    });
+    let mut tags: Vec<_> = video.chain(audio).collect();
+    // video first at the same timestamp, the sort is stable
+    tags.sort_by_key(|&(timestamp, _)| timestamp);
+    for (timestamp, tag_type) in tags {
+        let data: &[u8] = match tag_type {
+            // keyframe, AVC, NALU packet at composition time 0
+            9 => &[0x17, 0x01, 0, 0, 0],
+            // AAC 44kHz 16 bit stereo, raw frame
+            _ => &[0xaf, 0x01, 0x21],
+        };
+        out.extend(tag_bytes(tag_type, timestamp, data));
+    }
+    out
+}
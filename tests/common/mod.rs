@@ -0,0 +1,43 @@
+// every test file only uses some of these
+#![allow(dead_code)]
+
+pub mod flv_writer;
+
+use flv_keyframes::{read_flv_tag, FlvTag, PatchRegion};
+
+/// A tag around `data` as written in a file, with its pre tag size.
+pub fn tag_bytes(tag_type: u8, timestamp: u32, data: &[u8]) -> Vec<u8> {
+    let mut tag = vec![tag_type];
+    tag.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    tag.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+    tag.extend_from_slice(&[(timestamp >> 24) as u8, 0, 0, 0]);
+    tag.extend_from_slice(data);
+    tag.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    tag
+}
+
+/// Reads back the tag `bytes` as if it were at `offset` in a file.
+fn read_tag_at(bytes: &[u8], mut offset: u64) -> FlvTag {
+    read_flv_tag(&mut &bytes[..], &mut offset)
+        .expect("a valid tag")
+        .expect("a tag")
+}
+
+/// An H.264 keyframe video tag at `offset` in a file.
+pub fn video_keyframe(timestamp: u32, offset: u64) -> FlvTag {
+    // frame type 1, codec 7 is AVC, then a NALU packet at composition time 0
+    read_tag_at(&tag_bytes(9, timestamp, &[0x17, 0x01, 0, 0, 0]), offset)
+}
+
+/// A script tag at the start of a file, `data` is its AMF0 name and value.
+pub fn script_tag(data: Vec<u8>) -> FlvTag {
+    read_tag_at(&tag_bytes(18, 0, &data), 0)
+}
+
+pub fn region(origin_pos: u64, origin_size: u64, patched: Vec<u8>) -> PatchRegion {
+    PatchRegion {
+        origin_pos,
+        origin_size,
+        patched,
+    }
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::tag_bytes;
+use flv_keyframes::{read_flv, read_flv_header, FlvError, FlvHeader};
+
+const VALID: FlvHeader = FlvHeader {
+    version: 1,
+    has_audio: true,
+    has_video: true,
+    reserved_flags: 0,
+    data_offset: 9,
+};
+
+#[test]
+fn header_round_trips() {
+    let header = FlvHeader {
+        version: 1,
+        has_audio: true,
+        has_video: true,
+        reserved_flags: 0,
+        data_offset: 13,
+    };
+    let bytes = Vec::from(header);
+    assert_eq!(&bytes[..9], b"FLV\x01\x05\x00\x00\x00\x0d");
+    assert_eq!(bytes.len(), 13 + 4);
+
+    let mut offset = 0;
+    assert_eq!(
+        read_flv_header(&mut &bytes[..], &mut offset).unwrap(),
+        header
+    );
+    assert_eq!(offset, bytes.len() as u64);
+}
+
+#[test]
+fn rejects_invalid_headers() {
+    let invalid = [
+        FlvHeader {
+            version: 2,
+            ..VALID
+        },
+        FlvHeader {
+            reserved_flags: 0x08,
+            ..VALID
+        },
+        FlvHeader {
+            has_audio: false,
+            has_video: false,
+            ..VALID
+        },
+    ];
+    for header in invalid.iter() {
+        assert!(matches!(header.validate(), Err(FlvError::InvalidHeader(_))));
+        let mut input = Vec::from(*header);
+        assert!(matches!(
+            read_flv_header(&mut &input[..], &mut 0),
+            Err(FlvError::InvalidHeader(_))
+        ));
+
+        // lenient reads find the tags behind it all the same
+        input.extend(tag_bytes(9, 0, &[0x17, 0x01, 0, 0, 0]));
+        let tags = read_flv(&input[..])
+            .lenient(true)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].byte_offset, 13);
+    }
+}
+
+#[test]
+fn rejects_data_offsets_inside_the_header() {
+    let header = FlvHeader {
+        data_offset: 8,
+        ..VALID
+    };
+    assert!(matches!(header.validate(), Err(FlvError::InvalidHeader(_))));
+    let input = Vec::from(header);
+    assert!(matches!(
+        read_flv_header(&mut &input[..], &mut 0),
+        Err(FlvError::InvalidHeader(_))
+    ));
+    assert!(matches!(
+        read_flv(&input[..]).lenient(true).next(),
+        Some(Err(FlvError::InvalidHeader(_)))
+    ));
+}
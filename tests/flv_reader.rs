@@ -0,0 +1,115 @@
+mod common;
+
+use common::tag_bytes;
+use flv_keyframes::{
+    flv_codec::FrameType, frame_type_from_u8, read_flv, read_flv_with_offsets, FlvError, FlvHeader,
+    FlvTagData,
+};
+
+/// An H.264 keyframe NALU as tag data.
+const KEYFRAME: &[u8] = &[0x17, 0x01, 0, 0, 0];
+
+#[test]
+fn keeps_reading_after_unknown_tag_types() {
+    let mut input = Vec::from(FlvHeader {
+        version: 1,
+        has_audio: false,
+        has_video: true,
+        reserved_flags: 0,
+        data_offset: 9,
+    });
+    input.extend(tag_bytes(9, 0, KEYFRAME));
+    input.extend(tag_bytes(0x16, 500, b"unknown"));
+    input.extend(tag_bytes(9, 1000, KEYFRAME));
+
+    let tags = read_flv_with_offsets(&input[..])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(tags.len(), 3);
+    match &tags[1].1.data {
+        FlvTagData::Other { tag_type, data, .. } => {
+            assert_eq!(*tag_type, 0x16);
+            assert_eq!(data, b"unknown");
+        }
+        data => panic!("not an unknown tag: {:?}", data),
+    }
+    assert_eq!(tags[1].1.timestamp_ms(), 500);
+    assert!(tags[2].1.is_keyframe());
+    assert_eq!(tags[2].1.timestamp_ms(), 1000);
+    assert_eq!(tags[2].0, 13 + 2 * (11 + 4) + KEYFRAME.len() as u64 + 7);
+}
+
+#[test]
+fn reads_extended_timestamps() {
+    let tag = common::video_keyframe(0x0123_4567, 1000);
+    assert!(tag.is_keyframe());
+    assert_eq!(tag.byte_offset, 1000);
+    assert_eq!(tag.timestamp_ms(), 0x0123_4567);
+}
+
+#[test]
+fn wrong_pre_tag_sizes_fail_strict_reads() {
+    let mut input = Vec::from(FlvHeader {
+        version: 1,
+        has_audio: false,
+        has_video: true,
+        reserved_flags: 0,
+        data_offset: 9,
+    });
+    let mut first = tag_bytes(9, 0, KEYFRAME);
+    let len = first.len();
+    first[len - 4..].copy_from_slice(&7u32.to_be_bytes());
+    input.extend(first);
+    input.extend(tag_bytes(9, 1000, KEYFRAME));
+
+    let mut tags = read_flv(&input[..]);
+    match tags.next().unwrap() {
+        Err(FlvError::InvalidPreviousTagSize {
+            expected,
+            got,
+            position,
+            tag,
+        }) => {
+            assert_eq!(expected, 11 + KEYFRAME.len() as u32);
+            assert_eq!(got, 7);
+            assert_eq!(position, 13);
+            assert!(tag.is_keyframe());
+        }
+        tag => panic!("not a pre tag size error: {:?}", tag),
+    }
+    // the reader is at the next tag
+    assert_eq!(tags.next().unwrap().unwrap().timestamp_ms(), 1000);
+    assert!(tags.next().is_none());
+
+    let tags = read_flv(&input[..])
+        .lenient(true)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(tags.len(), 2);
+    assert_eq!(tags[0].byte_offset, 13);
+    assert_eq!(tags[1].timestamp_ms(), 1000);
+}
+
+#[test]
+fn frame_types_from_their_nibble() {
+    assert!(matches!(frame_type_from_u8(1), Ok(FrameType::KeyFrame)));
+    assert!(matches!(frame_type_from_u8(2), Ok(FrameType::InterFrame)));
+    assert!(matches!(
+        frame_type_from_u8(3),
+        Ok(FrameType::DisposableInterFrame)
+    ));
+    assert!(matches!(
+        frame_type_from_u8(4),
+        Ok(FrameType::GeneratedKeyFrame)
+    ));
+    assert!(matches!(
+        frame_type_from_u8(5),
+        Ok(FrameType::VideoInfoOrCommandFrame)
+    ));
+    for v in &[0, 6, 0xf] {
+        assert!(matches!(
+            frame_type_from_u8(*v),
+            Err(FlvError::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+use flv_keyframes::{generate_patch, read_patch_metadata, SkipReason};
+use std::path::PathBuf;
+use tokio::{fs::File, prelude::*};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+#[tokio::test]
+async fn patches_sample_byte_exact() {
+    let file = File::open(fixture("sample.flv")).await.unwrap();
+    let patch = generate_patch(file)
+        .await
+        .unwrap()
+        .patch
+        .expect("sample has no keyframes");
+
+    let file = File::open(fixture("sample.flv")).await.unwrap();
+    let mut reader = patch.patch_reader(file).await.unwrap();
+    let mut patched = vec![];
+    reader.read_to_end(&mut patched).await.unwrap();
+
+    let expected = std::fs::read(fixture("sample.patched.flv")).unwrap();
+    assert_eq!(reader.len(), expected.len() as u64);
+    assert_eq!(patched, expected);
+}
+
+#[tokio::test]
+async fn leaves_patched_sample_alone() {
+    let file = File::open(fixture("sample.patched.flv")).await.unwrap();
+    let result = generate_patch(file).await.unwrap();
+    assert!(result.patch.is_none());
+    assert_eq!(result.reason_skipped, Some(SkipReason::AlreadyHasKeyframes));
+}
+
+#[tokio::test]
+async fn keyframe_positions_follow_data_offset() {
+    // the sample with 4 extra header bytes, data_offset 13
+    let sample = std::fs::read(fixture("sample.flv")).unwrap();
+    let mut input = b"FLV\x01\x05\x00\x00\x00\x0d".to_vec();
+    input.extend_from_slice(&[0xff; 4]);
+    input.extend_from_slice(&sample[9..]);
+    let path = std::env::temp_dir().join(format!("data_offset_13_{}.flv", std::process::id()));
+    std::fs::write(&path, &input).unwrap();
+
+    let file = File::open(&path).await.unwrap();
+    let patch = generate_patch(file).await.unwrap().patch.unwrap();
+    let patched = patch.apply_to_bytes(&input);
+    std::fs::remove_file(&path).unwrap();
+
+    let (keyframes, _) = read_patch_metadata(&patch).unwrap().unwrap();
+    assert_eq!(keyframes.len(), 3);
+    for (position, _) in keyframes.iter() {
+        let tag = &patched[position as usize..];
+        // a video tag whose frame type is a key frame
+        assert_eq!(tag[0], 9);
+        assert_eq!(tag[11] >> 4, 1);
+    }
+}
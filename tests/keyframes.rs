@@ -0,0 +1,254 @@
+use amf::amf0::{self, Value};
+use flv_keyframes::{FlvError, Keyframes};
+use proptest::prelude::*;
+
+/// Keyframes with increasing times, like the ones read from a file.
+fn keyframes() -> impl Strategy<Value = Keyframes> {
+    prop::collection::vec((1..10_000u64, 0.0..10.0f64), 0..200).prop_map(|steps| {
+        let mut keyframes = Keyframes::new();
+        let (mut position, mut time) = (0, 0.0);
+        for (step, interval) in steps {
+            position += step;
+            time += interval;
+            keyframes.add(position, time);
+        }
+        keyframes
+    })
+}
+
+proptest! {
+    #[test]
+    fn downsample_keeps_ends_and_order(keyframes in keyframes(), target_count in 0..300usize) {
+        let downsampled = keyframes.downsample(target_count);
+        prop_assert_eq!(downsampled.len(), target_count.min(keyframes.len()));
+        if target_count > 0 {
+            prop_assert_eq!(downsampled.first(), keyframes.first());
+        }
+        if target_count > 1 {
+            prop_assert_eq!(downsampled.last(), keyframes.last());
+        }
+        let pairs: Vec<_> = downsampled.iter().collect();
+        for pair in pairs.windows(2) {
+            prop_assert!(pair[0].0 < pair[1].0);
+            prop_assert!(pair[0].1 <= pair[1].1);
+        }
+    }
+
+    #[test]
+    fn filter_by_time_range_keeps_window(
+        keyframes in keyframes(),
+        start in 0.0..1000.0f64,
+        length in -10.0..1000.0f64,
+    ) {
+        let end = start + length;
+        let filtered = keyframes.filter_by_time_range(start, end);
+        let expected: Vec<_> = keyframes
+            .iter()
+            .filter(|&(_, time)| start <= time && time <= end)
+            .collect();
+        prop_assert_eq!(filtered.iter().collect::<Vec<_>>(), expected);
+        prop_assert_eq!(filtered.fileposition_adjustment, 0.0);
+    }
+}
+
+#[test]
+fn to_json_applies_fileposition_adjustment() {
+    let mut keyframes = Keyframes::new();
+    keyframes.add(100, 0.0);
+    keyframes.add(200, 1.5);
+    assert_eq!(
+        keyframes.with_fileposition_adjustment(50.0).to_json(),
+        serde_json::json!({"filepositions": [150, 250], "times": [0.0, 1.5]})
+    );
+}
+
+proptest! {
+    #[test]
+    fn sort_orders_times_and_keeps_pairs(
+        pairs in prop::collection::vec((0..10_000u64, 0.0..100.0f64), 0..100),
+    ) {
+        let mut keyframes = Keyframes::new();
+        for &(position, time) in &pairs {
+            keyframes.add(position, time);
+        }
+        keyframes.sort();
+        let sorted: Vec<_> = keyframes.iter().collect();
+        prop_assert!(sorted.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        let mut expected = pairs.clone();
+        expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+        prop_assert_eq!(sorted, expected);
+    }
+}
+
+#[test]
+fn duration_and_average_interval() {
+    let mut keyframes = Keyframes::new();
+    assert_eq!(keyframes.duration(), None);
+    assert_eq!(keyframes.average_interval(), None);
+
+    keyframes.add(100, 1.0);
+    assert_eq!(keyframes.duration(), Some(0.0));
+    assert_eq!(keyframes.average_interval(), None);
+
+    // every 2.5s from 1s on
+    for i in 1..5u64 {
+        keyframes.add(100 + i * 1000, 1.0 + i as f64 * 2.5);
+    }
+    assert_eq!(keyframes.duration(), Some(10.0));
+    assert_eq!(keyframes.average_interval(), Some(2.5));
+}
+
+#[test]
+fn try_add_rejects_earlier_times() {
+    let mut keyframes = Keyframes::new();
+    keyframes.try_add(100, 1.0).unwrap();
+    keyframes.try_add(200, 1.0).unwrap();
+    assert!(keyframes.try_add(300, 0.5).is_err());
+    assert_eq!(keyframes.len(), 2);
+}
+
+fn keyframes_object(filepositions: Vec<Value>, times: Vec<Value>) -> Value {
+    amf0::object(
+        vec![
+            ("filepositions", amf0::array(filepositions)),
+            ("times", amf0::array(times)),
+        ]
+        .into_iter(),
+    )
+}
+
+#[test]
+fn from_amf0_reads_into_amf0() {
+    let mut keyframes = Keyframes::new();
+    keyframes.add(100, 0.0);
+    keyframes.add(200, 1.5);
+    keyframes.add(300, 1.5);
+    let (_, value) = keyframes.clone().into_amf0();
+    let read = Keyframes::from_amf0(&value).unwrap();
+    assert_eq!(
+        read.iter().collect::<Vec<_>>(),
+        keyframes.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(read.fileposition_adjustment, 0.0);
+
+    let empty = Keyframes::from_amf0(&keyframes_object(vec![], vec![])).unwrap();
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn from_amf0_rejects_invalid_keyframes() {
+    let numbers = |values: &[f64]| values.iter().copied().map(amf0::number).collect();
+    let invalid = vec![
+        amf0::number(1.0),
+        // different lengths
+        keyframes_object(numbers(&[100.0, 200.0]), numbers(&[0.0])),
+        // decreasing times
+        keyframes_object(numbers(&[100.0, 200.0]), numbers(&[1.0, 0.5])),
+        // not numbers
+        keyframes_object(vec![amf0::string("100")], numbers(&[0.0])),
+        keyframes_object(numbers(&[100.0]), vec![Value::Null]),
+        // not arrays
+        amf0::object(
+            vec![
+                ("filepositions", amf0::number(100.0)),
+                ("times", amf0::array(numbers(&[0.0]))),
+            ]
+            .into_iter(),
+        ),
+        // missing keys
+        amf0::object(vec![("times", amf0::array(numbers(&[0.0])))].into_iter()),
+        amf0::object(vec![("filepositions", amf0::array(numbers(&[100.0])))].into_iter()),
+        amf0::object(std::iter::empty::<(String, Value)>()),
+    ];
+    for value in invalid.iter() {
+        match Keyframes::from_amf0(value) {
+            Err(FlvError::InvalidKeyframes(_)) => {}
+            result => panic!("{:?} read as {:?}", value, result),
+        }
+    }
+}
+
+#[test]
+fn find_nearest_keyframes() {
+    let mut keyframes = Keyframes::new();
+    keyframes.add(100, 0.0);
+    keyframes.add(200, 2.0);
+    keyframes.add(300, 4.0);
+    // positions as in the file, like `first` and `last`
+    let keyframes = keyframes.with_fileposition_adjustment(50.0);
+
+    assert_eq!(keyframes.find_nearest_before(-1.0), None);
+    assert_eq!(keyframes.find_nearest_after(-1.0), Some((100, 0.0)));
+    // exact hits are both before and after
+    assert_eq!(keyframes.find_nearest_before(2.0), Some((200, 2.0)));
+    assert_eq!(keyframes.find_nearest_after(2.0), Some((200, 2.0)));
+    assert_eq!(keyframes.find_nearest_before(3.0), Some((200, 2.0)));
+    assert_eq!(keyframes.find_nearest_after(3.0), Some((300, 4.0)));
+    assert_eq!(keyframes.find_nearest_before(4.0), keyframes.last());
+    assert_eq!(keyframes.find_nearest_before(5.0), Some((300, 4.0)));
+    assert_eq!(keyframes.find_nearest_after(5.0), None);
+    assert_eq!(keyframes.find_nearest_after(0.0), keyframes.first());
+
+    let empty = Keyframes::new();
+    assert_eq!(empty.find_nearest_before(1.0), None);
+    assert_eq!(empty.find_nearest_after(1.0), None);
+}
+
+#[test]
+fn webvtt_chapters() {
+    let mut keyframes = Keyframes::new();
+    assert_eq!(keyframes.to_webvtt(None), "WEBVTT\n");
+    assert_eq!(keyframes.to_webvtt(Some(10.0)), "WEBVTT\n");
+
+    keyframes.add(100, 0.0);
+    assert_eq!(
+        keyframes.to_webvtt(None),
+        "WEBVTT\n\n1\n00:00:00.000 --> 00:00:00.000\nKeyframe 1\n"
+    );
+    assert_eq!(
+        keyframes.to_webvtt(Some(2.5)),
+        "WEBVTT\n\n1\n00:00:00.000 --> 00:00:02.500\nKeyframe 1\n"
+    );
+
+    keyframes.add(200, 61.25);
+    keyframes.add(300, 3723.004);
+    assert_eq!(
+        keyframes.to_webvtt(None),
+        "WEBVTT\n\
+         \n1\n00:00:00.000 --> 00:01:01.250\nKeyframe 1\n\
+         \n2\n00:01:01.250 --> 01:02:03.004\nKeyframe 2\n\
+         \n3\n01:02:03.004 --> 01:02:03.004\nKeyframe 3\n"
+    );
+    assert_eq!(
+        keyframes.to_webvtt(Some(3725.0)),
+        "WEBVTT\n\
+         \n1\n00:00:00.000 --> 00:01:01.250\nKeyframe 1\n\
+         \n2\n00:01:01.250 --> 01:02:03.004\nKeyframe 2\n\
+         \n3\n01:02:03.004 --> 01:02:05.000\nKeyframe 3\n"
+    );
+    // a total duration before the last keyframe doesn't end it early
+    assert!(keyframes
+        .to_webvtt(Some(1.0))
+        .ends_with("01:02:03.004 --> 01:02:03.004\nKeyframe 3\n"));
+}
+
+#[test]
+fn ffmetadata_chapters() {
+    let mut keyframes = Keyframes::new();
+    assert_eq!(keyframes.to_ffmetadata(5000), ";FFMETADATA1\n");
+
+    keyframes.add(100, 0.0);
+    keyframes.add(200, 2.5);
+    keyframes.add(300, 4.0015);
+    assert_eq!(
+        keyframes.to_ffmetadata(6000),
+        ";FFMETADATA1\n\
+         \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=2500\ntitle=Keyframe 1\n\
+         \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=2500\nEND=4002\ntitle=Keyframe 2\n\
+         \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=4002\nEND=6000\ntitle=Keyframe 3\n"
+    );
+    // the last chapter never ends before it starts
+    assert!(keyframes
+        .to_ffmetadata(1000)
+        .ends_with("START=4002\nEND=4002\ntitle=Keyframe 3\n"));
+}
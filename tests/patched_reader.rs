@@ -0,0 +1,216 @@
+mod common;
+
+use common::region;
+use flv_keyframes::{Patch, SyncPatchedReader};
+use proptest::prelude::*;
+use std::io::{Read, Seek, SeekFrom};
+
+/// An input file and a valid patch of it, regions stay inside the input.
+fn input_and_patch() -> impl Strategy<Value = (Vec<u8>, Patch)> {
+    prop::collection::vec(any::<u8>(), 0..256).prop_flat_map(|input| {
+        let len = input.len() as u64;
+        let region = (0..=len, 0..=len, prop::collection::vec(any::<u8>(), 0..32)).prop_map(
+            move |(origin_pos, origin_size, patched)| {
+                region(origin_pos, origin_size.min(len - origin_pos), patched)
+            },
+        );
+        prop::collection::vec(region, 0..4).prop_filter_map("overlapping regions", move |regions| {
+            Some((input.clone(), Patch::new(regions).ok()?))
+        })
+    })
+}
+
+/// Reads until `buf` is full or the end is reached.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).unwrap() {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    filled
+}
+
+proptest! {
+    #[test]
+    fn reads_like_applied_patch(
+        (input, patch) in input_and_patch(),
+        ops in prop::collection::vec((0..300u64, 0..64usize), 1..16),
+    ) {
+        let expected = patch.apply_to_bytes(&input);
+        let mut reader = SyncPatchedReader::new(input, patch);
+        prop_assert_eq!(reader.len(), expected.len() as u64);
+
+        let mut whole = vec![];
+        reader.read_to_end(&mut whole).unwrap();
+        prop_assert_eq!(&whole, &expected);
+
+        // remaining is what is left of len, nothing past the end
+        let consistent = |reader: &SyncPatchedReader| {
+            reader.tell() + reader.remaining() == reader.len().max(reader.tell())
+        };
+        for (pos, size) in ops {
+            prop_assert_eq!(reader.seek(SeekFrom::Start(pos)).unwrap(), pos);
+            prop_assert_eq!(reader.tell(), pos);
+            prop_assert!(consistent(&reader));
+            // a clone reads from the same position on its own
+            let mut replay = reader.clone();
+            let mut buf = vec![0u8; size];
+            let n = read_full(&mut reader, &mut buf);
+            prop_assert_eq!(reader.tell(), pos + n as u64);
+            prop_assert!(consistent(&reader));
+            let start = (pos as usize).min(expected.len());
+            let end = (start + size).min(expected.len());
+            prop_assert_eq!(&buf[..n], &expected[start..end]);
+            let mut again = vec![0u8; size];
+            prop_assert_eq!(read_full(&mut replay, &mut again), n);
+            prop_assert_eq!(&again, &buf);
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn merged_halves_apply_like_whole((input, patch) in input_and_patch(), split in 0..4usize) {
+        let regions = patch.regions().to_vec();
+        let split = split.min(regions.len());
+        let a = Patch::new(regions[..split].to_vec()).unwrap();
+        let b = Patch::new(regions[split..].to_vec()).unwrap();
+        let merged = Patch::merge(a, b).unwrap();
+        prop_assert_eq!(merged.apply_to_bytes(&input), patch.apply_to_bytes(&input));
+    }
+}
+
+#[test]
+fn merge_rejects_overlap() {
+    let a = Patch::new(vec![region(0, 10, vec![])]).unwrap();
+    let b = Patch::new(vec![region(5, 10, vec![])]).unwrap();
+    assert!(Patch::merge(a, b).is_err());
+}
+
+#[test]
+fn size_delta_and_identity_at_the_edges() {
+    let empty = Patch::default();
+    assert_eq!(empty.size_delta(), 0);
+    assert!(empty.is_identity());
+    // a region that replaces nothing with nothing changes nothing either
+    let noop = Patch::new(vec![region(4, 0, vec![])]).unwrap();
+    assert_eq!(noop.size_delta(), 0);
+    assert!(noop.is_identity());
+
+    let insertion = Patch::new(vec![region(4, 0, b"abc".to_vec())]).unwrap();
+    assert_eq!(insertion.size_delta(), 3);
+    assert!(!insertion.is_identity());
+    let deletion = Patch::new(vec![region(4, 5, vec![])]).unwrap();
+    assert_eq!(deletion.size_delta(), -5);
+    assert!(!deletion.is_identity());
+    // same length but other bytes
+    let replacement = Patch::new(vec![region(4, 3, b"abc".to_vec())]).unwrap();
+    assert_eq!(replacement.size_delta(), 0);
+    assert!(!replacement.is_identity());
+}
+
+proptest! {
+    #[test]
+    fn round_trips_through_bytes((input, patch) in input_and_patch()) {
+        let read = Patch::from_bytes(&patch.to_bytes().unwrap()).unwrap();
+        prop_assert_eq!(read.apply_to_bytes(&input), patch.apply_to_bytes(&input));
+        let read = patch.compress().unwrap().decompress().unwrap();
+        prop_assert_eq!(read.apply_to_bytes(&input), patch.apply_to_bytes(&input));
+    }
+}
+
+#[tokio::test]
+async fn reads_lines_across_regions() {
+    use tokio::io::AsyncBufReadExt;
+
+    // an AMF0 string replacing the middle of the second line
+    let input = b"first line\nsecond line\nthird line\n".to_vec();
+    let mut amf0 = vec![0x02, 0x00, 0x0e];
+    amf0.extend_from_slice(b"onMeta\nData\nx");
+    let patch = Patch::new(vec![region(14, 4, amf0)]).unwrap();
+    let expected = patch.apply_to_bytes(&input);
+
+    let mut reader = patch
+        .patch_reader(std::io::Cursor::new(input))
+        .await
+        .unwrap();
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line).await.unwrap() {
+            0 => break,
+            _ => lines.push(line),
+        }
+    }
+    let expected = String::from_utf8_lossy(&expected);
+    assert_eq!(lines, expected.split_inclusive('\n').collect::<Vec<_>>());
+    assert_eq!(lines[1], "sec\u{2}\0\u{e}onMeta\n");
+}
+
+#[tokio::test]
+async fn saves_and_reads_back_files() {
+    let patch = Patch::new(vec![region(3, 2, b"patched".to_vec())]).unwrap();
+    let input = b"original input".to_vec();
+    let path = std::env::temp_dir().join(format!("patch_file_{}.binpatch", std::process::id()));
+
+    patch.save(&path).await.unwrap();
+    let read = Patch::from_file(&path).await.unwrap();
+    assert_eq!(read.apply_to_bytes(&input), patch.apply_to_bytes(&input));
+    patch.compress().unwrap().save(&path).await.unwrap();
+    let read = Patch::from_file(&path).await.unwrap();
+    assert_eq!(read.apply_to_bytes(&input), patch.apply_to_bytes(&input));
+
+    std::fs::remove_file(&path).unwrap();
+    let e = Patch::from_file(&path).await.unwrap_err();
+    assert!(e.to_string().contains("patch_file_"), "{}", e);
+}
+
+#[tokio::test]
+async fn failed_save_leaves_no_file() {
+    let patch = Patch::new(vec![region(3, 2, b"patched".to_vec())]).unwrap();
+    // renaming over a directory fails after the data is written aside
+    let path = std::env::temp_dir().join(format!("patch_dir_{}.binpatch", std::process::id()));
+    std::fs::create_dir_all(&path).unwrap();
+
+    assert!(patch.save(&path).await.is_err());
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    assert!(!std::path::Path::new(&tmp_path).exists());
+    assert!(path.is_dir());
+    std::fs::remove_dir(&path).unwrap();
+}
+
+proptest! {
+    #[test]
+    fn seeks_from_end_and_current((input, patch) in input_and_patch(), back in 0..512u64) {
+        let expected = patch.apply_to_bytes(&input);
+        let len = expected.len() as u64;
+        let mut reader = SyncPatchedReader::new(input, patch);
+        match reader.seek(SeekFrom::End(-(back as i64))) {
+            Ok(pos) => prop_assert_eq!(pos, len - back),
+            Err(_) => prop_assert!(back > len),
+        }
+        let pos = reader.stream_position().unwrap();
+        // seeking before the start fails and keeps the position
+        prop_assert!(reader.seek(SeekFrom::Current(-(pos as i64) - 1)).is_err());
+        prop_assert!(reader.seek(SeekFrom::End(i64::MIN)).is_err());
+        prop_assert_eq!(reader.stream_position().unwrap(), pos);
+    }
+}
+
+#[tokio::test]
+async fn gives_back_the_origin_reader() {
+    use tokio::io::AsyncReadExt;
+
+    let patch = Patch::new(vec![region(0, 1, b"patched".to_vec())]).unwrap();
+    let mut reader = patch
+        .patch_reader(std::io::Cursor::new(b"origin".to_vec()))
+        .await
+        .unwrap();
+    let mut patched = vec![];
+    reader.read_to_end(&mut patched).await.unwrap();
+    assert_eq!(patched, b"patchedrigin");
+    assert_eq!(reader.into_inner().into_inner(), b"origin");
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{
+    flv_writer::{write_synthetic_flv, SyntheticFlvConfig},
+    script_tag, tag_bytes,
+};
+use flv_keyframes::{generate_patch_from_reader, read_flv_tag, FlvTagData, PatchOptions};
+
+#[test]
+fn decodes_name_and_value() {
+    // "onCuePoint" followed by the number 1.5
+    let mut data = b"\x02\x00\x0aonCuePoint\x00".to_vec();
+    data.extend_from_slice(&1.5f64.to_be_bytes());
+    match script_tag(data).data {
+        FlvTagData::Script(script) => {
+            assert_eq!(script.name, "onCuePoint");
+            assert!(!script.is_metadata());
+            assert_eq!(script.value.try_as_f64(), Some(1.5));
+        }
+        data => panic!("not a script tag: {:?}", data),
+    }
+}
+
+#[test]
+fn rejects_hostile_values() {
+    let name = b"\x02\x00\x0aonMetaData";
+    // a long string claiming 4GB, strict arrays nested 1000 deep
+    let long_string = [&name[..], b"\x0c\xff\xff\xff\xff"].concat();
+    let mut nested = name.to_vec();
+    for _ in 0..1000 {
+        nested.extend_from_slice(b"\x0a\x00\x00\x00\x01");
+    }
+    nested.push(0x05);
+    for data in &[long_string, nested] {
+        assert!(read_flv_tag(&mut &tag_bytes(18, 0, data)[..], &mut 0).is_err());
+    }
+}
+
+#[tokio::test]
+async fn keeps_undecodable_cue_points() {
+    // "onCuePoint" followed by an AMF3 switch, then a reference
+    for value in &[&b"\x11\x04\x01"[..], &b"\x07\x00\x00"[..]] {
+        let data = [&b"\x02\x00\x0aonCuePoint"[..], value].concat();
+        match script_tag(data.clone()).data {
+            FlvTagData::Other {
+                tag_type,
+                data: kept,
+                ..
+            } => {
+                assert_eq!(tag_type, 18);
+                assert_eq!(kept, data);
+            }
+            data => panic!("decoded anyway: {:?}", data),
+        }
+
+        // a file with one still gets its patch
+        let mut input = write_synthetic_flv(&SyntheticFlvConfig::default());
+        input.extend(tag_bytes(18, 500, &data));
+        let result = generate_patch_from_reader(
+            std::io::Cursor::new(input.clone()),
+            input.len() as u64,
+            PatchOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(result.patch.is_some());
+    }
+}
+
+#[test]
+fn keeps_raw_data_with_feature() {
+    let data = b"\x02\x00\x0aonMetaData\x05";
+    let tag = script_tag(data.to_vec());
+    if cfg!(feature = "raw-data") {
+        assert_eq!(tag.data_as_bytes(), Some(&data[..]));
+    } else {
+        assert_eq!(tag.data_as_bytes(), None);
+    }
+}
@@ -0,0 +1,249 @@
+mod common;
+
+use amf::amf0;
+use bytecodec::{io::IoEncodeExt, Encode};
+use common::{
+    flv_writer::{write_synthetic_flv, SyntheticFlvConfig},
+    tag_bytes,
+};
+use flv_keyframes::{
+    flv_codec::{ScriptDataTag, StreamId, Tag, TagEncoder},
+    generate_patch_from_reader, read_flv_with_offsets, read_keyframes, read_patch_metadata,
+    FlvError, FlvTagData, PatchOptions, SkipReason, SyncPatchedReader,
+};
+use std::{
+    io::{Cursor, Read},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+};
+
+async fn patch_and_read(config: &SyntheticFlvConfig) {
+    let input = write_synthetic_flv(config);
+    let result = generate_patch_from_reader(
+        Cursor::new(input.clone()),
+        input.len() as u64,
+        PatchOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.keyframe_count, config.num_keyframes);
+    assert_eq!(
+        result.total_tags,
+        config.num_keyframes + config.num_audio_tags + config.include_script_tag as usize
+    );
+    let patch = result.patch.unwrap();
+
+    let mut patched = vec![];
+    SyncPatchedReader::new(input, patch.clone())
+        .read_to_end(&mut patched)
+        .unwrap();
+    let (inserted, _) = read_patch_metadata(&patch).unwrap().unwrap();
+    let (found, _) = read_keyframes(&patched[..]).unwrap();
+    assert_eq!(
+        inserted.iter().collect::<Vec<_>>(),
+        found.iter().collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn patches_synthetic_files() {
+    patch_and_read(&SyntheticFlvConfig::default()).await;
+    patch_and_read(&SyntheticFlvConfig {
+        include_script_tag: false,
+        ..SyntheticFlvConfig::default()
+    })
+    .await;
+    patch_and_read(&SyntheticFlvConfig {
+        num_audio_tags: 0,
+        keyframe_interval_ms: 40,
+        ..SyntheticFlvConfig::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn skips_audio_only_files() {
+    let input = write_synthetic_flv(&SyntheticFlvConfig {
+        num_keyframes: 0,
+        ..SyntheticFlvConfig::default()
+    });
+    let result = generate_patch_from_reader(Cursor::new(input), 0, PatchOptions::default())
+        .await
+        .unwrap();
+    assert!(result.patch.is_none());
+    assert_eq!(result.reason_skipped, Some(SkipReason::NoKeyframes));
+}
+
+#[test]
+fn offsets_follow_tag_sizes() {
+    let input = write_synthetic_flv(&SyntheticFlvConfig::default());
+    let mut expected = 13;
+    for tag in read_flv_with_offsets(&input[..]) {
+        let (offset, tag) = tag.unwrap();
+        assert_eq!(offset, expected);
+        expected += tag.tag_size as u64 + 4;
+    }
+    assert_eq!(expected, input.len() as u64);
+}
+
+#[tokio::test]
+async fn keyframes_before_the_script_tag_stay_put() {
+    let keyframe: &[u8] = &[0x17, 0x01, 0, 0, 0];
+    let mut input = b"FLV\x01\x01\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    input.extend(tag_bytes(9, 0, keyframe));
+    input.extend(tag_bytes(
+        18,
+        0,
+        b"\x02\x00\x0aonMetaData\x08\x00\x00\x00\x00\x00\x00\x09",
+    ));
+    input.extend(tag_bytes(9, 1000, keyframe));
+    input.extend(tag_bytes(9, 1500, &[0x27, 0x01, 0, 0, 0]));
+    input.extend(tag_bytes(9, 2000, keyframe));
+    let result = generate_patch_from_reader(
+        Cursor::new(input.clone()),
+        input.len() as u64,
+        PatchOptions::default(),
+    )
+    .await
+    .unwrap();
+    let patch = result.patch.unwrap();
+    let patched = patch.apply_to_bytes(&input);
+
+    let (keyframes, _) = read_patch_metadata(&patch).unwrap().unwrap();
+    assert_eq!(keyframes.len(), 3);
+    assert_eq!(keyframes.first().unwrap().0, 13);
+    for (position, _) in keyframes.iter() {
+        let tag = &patched[position as usize..];
+        // a video tag whose frame type is a key frame
+        assert_eq!(tag[0], 9);
+        assert_eq!(tag[11] >> 4, 1);
+    }
+}
+
+#[tokio::test]
+async fn patches_files_with_empty_keyframes() {
+    let keyframe: &[u8] = &[0x17, 0x01, 0, 0, 0];
+    let empty_objects: [&[u8]; 2] = [
+        // keyframes: {}
+        b"\x03\x00\x00\x09",
+        // keyframes: {filepositions: [], times: []}
+        b"\x03\x00\x0dfilepositions\x0a\x00\x00\x00\x00\x00\x05times\x0a\x00\x00\x00\x00\x00\x00\x09",
+    ];
+    for keyframes in empty_objects.iter() {
+        let mut metadata = b"\x02\x00\x0aonMetaData\x08\x00\x00\x00\x01\x00\x09keyframes".to_vec();
+        metadata.extend_from_slice(keyframes);
+        metadata.extend_from_slice(b"\x00\x00\x09");
+        let mut input = b"FLV\x01\x01\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+        input.extend(tag_bytes(18, 0, &metadata));
+        input.extend(tag_bytes(9, 0, keyframe));
+        input.extend(tag_bytes(9, 1000, keyframe));
+        let result = generate_patch_from_reader(
+            Cursor::new(input.clone()),
+            input.len() as u64,
+            PatchOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.reason_skipped, None);
+        let (keyframes, _) = read_patch_metadata(&result.patch.unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(keyframes.len(), 2);
+    }
+}
+
+#[test]
+fn tags_encode_back_to_their_bytes() {
+    let input = write_synthetic_flv(&SyntheticFlvConfig::default());
+    let mut scripts = 0;
+    for tag in read_flv_with_offsets(&input[..]) {
+        let (offset, tag) = tag.unwrap();
+        let written = &input[offset as usize..][..tag.tag_size as usize + 4];
+        // the data size in the header and the pre tag size agree with tag_size
+        assert_eq!(
+            u32::from_be_bytes([0, written[1], written[2], written[3]]),
+            tag.tag_size - 11
+        );
+        assert_eq!(&written[written.len() - 4..], &tag.tag_size.to_be_bytes());
+        if let Some(data) = tag.data_as_bytes() {
+            let encoded = tag_bytes(tag.data.tag_type(), tag.timestamp_ms(), data);
+            assert_eq!(encoded, written);
+        }
+        // encoded again from the decoded values, like the patched metadata
+        if let FlvTagData::Script(script) = &tag.data {
+            let mut data = vec![];
+            amf0::string(script.name.clone())
+                .write_to(&mut data)
+                .unwrap();
+            script.value.write_to(&mut data).unwrap();
+            let mut encoder = TagEncoder::<Vec<u8>>::new();
+            encoder
+                .start_encoding(Tag::ScriptData(ScriptDataTag {
+                    timestamp: script.timestamp,
+                    stream_id: StreamId::new(0).unwrap(),
+                    data,
+                }))
+                .unwrap();
+            let mut encoded = vec![];
+            encoder.encode_all(&mut encoded).unwrap();
+            encoded.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+            assert_eq!(encoded, written);
+            scripts += 1;
+        }
+    }
+    assert_eq!(scripts, 1);
+}
+
+/// Hands out a few bytes per read, blocking at the `block_at`th read until
+/// `gate` is dropped, after telling `blocked`.
+struct GatedReader {
+    inner: Cursor<Vec<u8>>,
+    reads: Arc<AtomicUsize>,
+    block_at: usize,
+    blocked: mpsc::Sender<()>,
+    gate: mpsc::Receiver<()>,
+}
+
+impl Read for GatedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.reads.fetch_add(1, Ordering::SeqCst) == self.block_at {
+            let _ = self.blocked.send(());
+            let _ = self.gate.recv();
+        }
+        let len = buf.len().min(16);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+#[tokio::test]
+async fn cancelled_scan_stops_reading() {
+    let input = write_synthetic_flv(&SyntheticFlvConfig::default());
+    let all_reads = input.len() / 16;
+    let reads = Arc::new(AtomicUsize::new(0));
+    let (blocked, is_blocked) = mpsc::channel();
+    let (open, gate) = mpsc::channel();
+    let reader = GatedReader {
+        inner: Cursor::new(input),
+        reads: reads.clone(),
+        block_at: 10,
+        blocked,
+        gate,
+    };
+    // what the server does with --timeout-ms
+    let options = PatchOptions::default();
+    let guard = options.cancel.clone().drop_guard();
+    let generate = tokio::spawn(generate_patch_from_reader(reader, 0, options));
+    tokio::task::spawn_blocking(move || is_blocked.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    drop(guard);
+    drop(open);
+
+    // the blocking scan notices the cancellation at the next tag
+    let result = generate.await.unwrap();
+    assert!(matches!(result, Err(FlvError::Cancelled)), "{:?}", result);
+    assert!(reads.load(Ordering::SeqCst) < all_reads);
+}